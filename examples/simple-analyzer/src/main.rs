@@ -16,18 +16,14 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use std::{
-    collections::VecDeque,
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-    },
-    time::Duration,
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
 };
 
 use anyhow::Result;
 use clap::Parser;
-use frame_analyzer::Analyzer;
+use frame_analyzer::{Analyzer, AnalyzerEvent};
 
 /// Simple frame analyzer, print frametime on the screen
 #[derive(Parser, Debug)]
@@ -42,6 +38,8 @@ fn main() -> Result<()> {
     let arg = Args::parse();
     let pid = arg.pid;
 
+    println!("{}", frame_analyzer::preflight());
+
     let mut analyzer = Analyzer::new()?;
     analyzer.attach_app(pid)?;
 
@@ -49,26 +47,36 @@ fn main() -> Result<()> {
 
     {
         let running = running.clone();
+        let waker = analyzer.waker();
         ctrlc::set_handler(move || {
             running.store(false, Ordering::Release);
+            let _ = waker.wake();
         })?;
     }
 
-    let mut buffer = VecDeque::with_capacity(120);
-
     while running.load(Ordering::Acquire) {
-        if let Some((pid, frametime)) = analyzer.recv() {
-            println!("frametime: {frametime:?}, pid: {pid}");
-            if buffer.len() >= 120 {
-                buffer.pop_back();
+        match analyzer.recv_event() {
+            Some(AnalyzerEvent::Frame(event)) => {
+                let (pid, frametime) = (event.pid, event.frametime);
+                println!("frametime: {frametime:?}, pid: {pid}");
+                let window = analyzer.frame_stats().window(pid, event.surface);
+                if let Some(fps) = window
+                    .filter(|window| window.len() == 120)
+                    .and_then(|window| window.fps())
+                {
+                    println!("{fps}");
+                }
             }
-            buffer.push_front(frametime);
-            if buffer.len() == 120 {
-                let fps = 1.0
-                    / (buffer.iter().copied().sum::<Duration>() / buffer.len() as u32)
-                        .as_secs_f64();
-                println!("{fps}");
+            Some(AnalyzerEvent::TargetExited { pid }) => {
+                println!("target exited, pid: {pid}");
+                let stats = analyzer.frame_stats();
+                for (_, surface) in stats.surfaces().filter(|(stats_pid, _)| *stats_pid == pid) {
+                    println!("surface {surface:#x}: {:?}", stats.stats(pid, surface));
+                }
+                break;
             }
+            Some(_) => (),
+            None => break,
         }
     }
 
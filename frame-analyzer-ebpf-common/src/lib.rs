@@ -18,14 +18,77 @@
  */
 #![no_std]
 
+/// [`FrameSignal`]的布局版本，布局变化时递增
+pub const FRAME_SIGNAL_VERSION: u32 = 2;
+
+/// 该surface的上一帧因ring buffer已满被丢弃，这一帧的帧时间可能包含了两帧
+pub const FRAME_FLAG_MERGED: u32 = 1 << 0;
+
+/// 内核中线程名的最大长度，包含结尾的NUL
+pub const TASK_COMM_LEN: usize = 16;
+
+/// eBPF程序与用户态共享的帧事件
+///
+/// `version`固定在最前面，用户态先检查版本再解析其余字段
 #[repr(C)]
 pub struct FrameSignal {
+    pub version: u32,
+    /// 提交该帧的cpu
+    pub cpu: u32,
     pub ktime_ns: u64,
-    pub buffer: usize,
+    /// `android::Surface`对象的地址，固定为64位，与用户态的位数无关
+    pub buffer: u64,
+    /// 进程id，即用户态的pid
+    pub tgid: u32,
+    /// 线程id，即内核意义上的pid
+    pub tid: u32,
+    /// 提交该帧的线程名，不足16字节时以NUL结尾
+    pub comm: [u8; TASK_COMM_LEN],
+    /// `FRAME_FLAG_*`的组合
+    pub flags: u32,
+    _reserved: u32,
 }
 
+// eBPF程序和用户态必须使用相同的布局
+const _: () = assert!(core::mem::size_of::<FrameSignal>() == 56);
+
 impl FrameSignal {
-    pub const fn new(ktime_ns: u64, buffer: usize) -> Self {
-        Self { ktime_ns, buffer }
+    pub const fn new(ktime_ns: u64, buffer: u64, tgid: u32, tid: u32) -> Self {
+        Self {
+            version: FRAME_SIGNAL_VERSION,
+            cpu: 0,
+            ktime_ns,
+            buffer,
+            tgid,
+            tid,
+            comm: [0; TASK_COMM_LEN],
+            flags: 0,
+            _reserved: 0,
+        }
+    }
+
+    /// 从`bpf_get_current_pid_tgid`的返回值构造
+    pub const fn from_pid_tgid(ktime_ns: u64, buffer: u64, pid_tgid: u64) -> Self {
+        Self::new(ktime_ns, buffer, (pid_tgid >> 32) as u32, pid_tgid as u32)
+    }
+
+    /// 填充提交线程所在的cpu和线程名
+    pub const fn with_task(mut self, cpu: u32, comm: [u8; TASK_COMM_LEN]) -> Self {
+        self.cpu = cpu;
+        self.comm = comm;
+        self
+    }
+
+    pub const fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub const fn is_merged(&self) -> bool {
+        self.flags & FRAME_FLAG_MERGED != 0
+    }
+
+    pub const fn is_current_version(&self) -> bool {
+        self.version == FRAME_SIGNAL_VERSION
     }
 }
@@ -0,0 +1,136 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! 两种程序变体共享的过滤、丢帧标记、嵌套调用去重和事件构造
+
+use aya_ebpf::{
+    helpers::{
+        bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_smp_processor_id,
+        bpf_ktime_get_ns,
+    },
+    macros::map,
+    maps::{HashMap, LruHashMap, PerCpuArray},
+    programs::ProbeContext,
+};
+
+use frame_analyzer_ebpf_common::{FRAME_FLAG_MERGED, FrameSignal, TASK_COMM_LEN};
+
+/// 允许上报的tgid，由用户态在运行时增删
+#[map]
+static PID_FILTER: HashMap<u32, u8> = HashMap::with_max_entries(1024, 0);
+
+/// 每个cpu上事件提交失败的次数
+#[map]
+static LOST_EVENTS: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+/// 丢过帧的surface，下一帧上报时带上`FRAME_FLAG_MERGED`
+#[map]
+static MERGED: LruHashMap<SurfaceKey, u8> = LruHashMap::with_max_entries(1024, 0);
+
+/// 每个线程最近一次上报的queueBuffer，一个重载转调另一个时只上报最外层的一次
+#[map]
+static LAST_ENTRY: LruHashMap<u32, LastEntry> = LruHashMap::with_max_entries(1024, 0);
+
+// 同一线程在这段时间内对同一个surface的再次进入是嵌套调用，远小于任何帧间隔
+const NESTED_WINDOW_NS: u64 = 200_000;
+
+#[repr(C)]
+struct LastEntry {
+    ktime_ns: u64,
+    buffer: u64,
+}
+
+#[repr(C)]
+struct SurfaceKey {
+    buffer: u64,
+    tgid: u32,
+    _pad: u32,
+}
+
+/// 一次最外层的`queueBuffer`调用，只有被允许的进程才会构造
+pub struct Target {
+    pid_tgid: u64,
+    key: SurfaceKey,
+}
+
+impl Target {
+    pub fn current(ctx: &ProbeContext) -> Option<Self> {
+        let pid_tgid = bpf_get_current_pid_tgid();
+        let tgid = (pid_tgid >> 32) as u32;
+        unsafe { PID_FILTER.get(&tgid) }?;
+        let buffer = ctx.arg::<u64>(0)?;
+        enter(pid_tgid as u32, buffer)?;
+
+        Some(Self {
+            pid_tgid,
+            key: SurfaceKey {
+                buffer,
+                tgid,
+                _pad: 0,
+            },
+        })
+    }
+
+    pub fn signal(&self) -> FrameSignal {
+        let mut flags = 0;
+        if unsafe { MERGED.get(&self.key) }.is_some() {
+            flags |= FRAME_FLAG_MERGED;
+        }
+
+        let ktime_ns = unsafe { bpf_ktime_get_ns() };
+        let cpu = unsafe { bpf_get_smp_processor_id() };
+        let comm = bpf_get_current_comm().unwrap_or([0; TASK_COMM_LEN]);
+        FrameSignal::from_pid_tgid(ktime_ns, self.key.buffer, self.pid_tgid)
+            .with_task(cpu, comm)
+            .with_flags(flags)
+    }
+
+    /// 事件已经提交，合并标记随这一帧上报后清除
+    pub fn submitted(&self, signal: &FrameSignal) {
+        if signal.is_merged() {
+            let _ = MERGED.remove(&self.key);
+        }
+    }
+
+    /// 事件没能提交，计数并让该surface的下一帧带上合并标记
+    pub fn lost(&self) {
+        if let Some(lost) = LOST_EVENTS.get_ptr_mut(0) {
+            unsafe { *lost += 1 };
+        }
+        let _ = MERGED.insert(&self.key, &1, 0);
+    }
+}
+
+// 返回None表示这是同一线程上嵌套的调用
+fn enter(tid: u32, buffer: u64) -> Option<()> {
+    let ktime_ns = unsafe { bpf_ktime_get_ns() };
+    if let Some(last) = unsafe { LAST_ENTRY.get(&tid) }
+        && last.buffer == buffer
+        && ktime_ns.saturating_sub(last.ktime_ns) < NESTED_WINDOW_NS
+    {
+        return None;
+    }
+
+    let _ = LAST_ENTRY.insert(&tid, &LastEntry { ktime_ns, buffer }, 0);
+    Some(())
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
@@ -19,37 +19,36 @@
 #![no_std]
 #![no_main]
 
+mod common;
+
 use aya_ebpf::{
-    helpers::bpf_ktime_get_ns,
     macros::{map, uprobe},
     maps::RingBuf,
     programs::ProbeContext,
 };
 
+use common::Target;
 use frame_analyzer_ebpf_common::FrameSignal;
 
+/// 大小只是默认值，用户态加载时会按配置覆盖
 #[map]
 static RING_BUF: RingBuf = RingBuf::with_byte_size(0x1000, 0);
 
 #[uprobe]
 pub fn frame_analyzer_ebpf(ctx: ProbeContext) -> u32 {
-    match try_frame_analyzer_ebpf(ctx) {
-        Ok(ret) => ret,
-        Err(ret) => ret,
-    }
-}
+    let Some(target) = Target::current(&ctx) else {
+        return 0;
+    };
 
-fn try_frame_analyzer_ebpf(ctx: ProbeContext) -> Result<u32, u32> {
-    if let Some(mut entry) = RING_BUF.reserve::<FrameSignal>(0) {
-        let ktime_ns = unsafe { bpf_ktime_get_ns() };
-        entry.write(FrameSignal::new(ktime_ns, ctx.arg::<usize>(0).unwrap()));
-        entry.submit(0);
-    }
+    let Some(mut entry) = RING_BUF.reserve::<FrameSignal>(0) else {
+        target.lost();
+        return 0;
+    };
 
-    Ok(0)
-}
+    let signal = target.signal();
+    target.submitted(&signal);
+    entry.write(signal);
+    entry.submit(0);
 
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    0
 }
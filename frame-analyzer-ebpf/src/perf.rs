@@ -0,0 +1,69 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! 没有`BPF_MAP_TYPE_RINGBUF`的内核(5.8以前)使用的变体，通过`PerfEventArray`上报
+
+#![no_std]
+#![no_main]
+
+mod common;
+
+use core::{ffi::c_void, mem};
+
+use aya_ebpf::{
+    EbpfContext,
+    bindings::BPF_F_CURRENT_CPU,
+    helpers::bpf_perf_event_output,
+    macros::{map, uprobe},
+    maps::PerfEventArray,
+    programs::ProbeContext,
+};
+
+use common::Target;
+use frame_analyzer_ebpf_common::FrameSignal;
+
+/// 每个cpu一个perf buffer，大小由用户态打开时决定
+#[map]
+static PERF_EVENTS: PerfEventArray<FrameSignal> = PerfEventArray::new(0);
+
+#[uprobe]
+pub fn frame_analyzer_ebpf(ctx: ProbeContext) -> u32 {
+    let Some(target) = Target::current(&ctx) else {
+        return 0;
+    };
+
+    let mut signal = target.signal();
+    // PerfEventArray::output不返回结果，直接调用helper才能知道buffer是否已满
+    let ret = unsafe {
+        bpf_perf_event_output(
+            ctx.as_ptr(),
+            (&raw const PERF_EVENTS).cast_mut().cast::<c_void>(),
+            BPF_F_CURRENT_CPU,
+            (&raw mut signal).cast::<c_void>(),
+            mem::size_of::<FrameSignal>() as u64,
+        )
+    };
+
+    if ret == 0 {
+        target.submitted(&signal);
+    } else {
+        target.lost();
+    }
+
+    0
+}
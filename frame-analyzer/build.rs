@@ -20,45 +20,55 @@
 use std::{
     env,
     fs,
-    path::Path,
+    path::{Path, PathBuf},
     process::Command,
 };
 
 use anyhow::{Context, Result}; // 替换Ok为Context，避免与std::result::Ok冲突
 
-// 定义固定的eBPF文件路径
-const EBPF_FILE_PATH: &str = "/workspaces/frame-analyzer-ebpf/ebpf_single_file/frame-analyzer-ebpf";
+// 仓库中预编译的eBPF文件，修改frame-analyzer-ebpf后需要重新编译并更新
+const EBPF_FILE_PATH: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../ebpf_single_file/frame-analyzer-ebpf"
+);
+// 给没有ring buffer的旧内核使用的perf变体
+const EBPF_PERF_FILE_PATH: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../ebpf_single_file/frame-analyzer-ebpf-perf"
+);
 
 fn main() -> Result<()> {
     // 跳过原有的编译逻辑，直接验证并拷贝指定路径的eBPF文件
-    copy_ebpf_file()?;
+    let prefix_dir = ebpf_target_dir()?;
+    copy_ebpf_file(EBPF_FILE_PATH, &prefix_dir)?;
+    copy_ebpf_file(EBPF_PERF_FILE_PATH, &prefix_dir)?;
     Ok(())
 }
 
-/// 验证指定路径的eBPF文件是否存在，并拷贝到编译输出目录
-fn copy_ebpf_file() -> Result<()> {
-    let ebpf_src = Path::new(EBPF_FILE_PATH);
-    // 检查文件是否存在
-    if !ebpf_src.exists() {
-        anyhow::bail!("eBPF文件不存在: {}", EBPF_FILE_PATH);
-    }
-
-    // 获取编译输出目录
+/// 创建编译输出目录中存放eBPF文件的目录（兼容原代码的输出结构）
+fn ebpf_target_dir() -> Result<PathBuf> {
     let out_dir = env::var("OUT_DIR").context("获取OUT_DIR环境变量失败")?;
-    let out_dir = Path::new(&out_dir);
-    let target_dir = out_dir.join("ebpf_target");
-    let _target_dir_str = target_dir.to_str().context("转换目标目录路径为字符串失败")?; // 修复错误处理
+    let target_dir = Path::new(&out_dir).join("ebpf_target");
 
-    // 创建目标目录（兼容原代码的输出结构）
     #[cfg(debug_assertions)]
-    let prefix_dir = target_dir.join("bpfel-unknown-none").join("debug"); // 移除多余的&，避免临时值引用
+    let prefix_dir = target_dir.join("bpfel-unknown-none").join("debug");
     #[cfg(not(debug_assertions))]
     let prefix_dir = target_dir.join("bpfel-unknown-none").join("release");
 
     fs::create_dir_all(&prefix_dir).context("创建eBPF目标目录失败")?;
+    Ok(prefix_dir)
+}
+
+/// 验证指定路径的eBPF文件是否存在，并拷贝到编译输出目录
+fn copy_ebpf_file(path: &str, prefix_dir: &Path) -> Result<()> {
+    println!("cargo:rerun-if-changed={path}");
+    let ebpf_src = Path::new(path);
+    // 检查文件是否存在
+    if !ebpf_src.exists() {
+        anyhow::bail!("eBPF文件不存在: {path}");
+    }
 
-    // 拷贝指定的eBPF文件到输出目录
-    let ebpf_dst = prefix_dir.join("frame-analyzer-ebpf");
+    let ebpf_dst = prefix_dir.join(ebpf_src.file_name().context("eBPF文件路径没有文件名")?);
     fs::copy(ebpf_src, ebpf_dst).context("拷贝eBPF文件失败")?;
     println!("cargo:info=成功拷贝eBPF文件到: {}", prefix_dir.display());
 
@@ -18,65 +18,249 @@
  */
 use std::{
     collections::{HashMap, VecDeque},
-    ptr,
+    mem, ptr,
     time::Duration,
 };
 
 use frame_analyzer_ebpf_common::FrameSignal;
 
-use crate::uprobe::UprobeHandler;
+use crate::{
+    SurfaceId,
+    selector::{SurfaceSelector, SurfaceView},
+};
 
 pub struct AnalyzeTarget {
-    pub uprobe: UprobeHandler,
-    buffers: HashMap<usize, (u64, VecDeque<Duration>)>,
+    history_len: usize,
+    idle: IdleThreshold,
+    buffers: HashMap<SurfaceId, Surface>,
+    selector: Option<Box<dyn SurfaceSelector>>, // 为空时使用Analyzer的默认策略
+}
+
+struct Surface {
+    timestamp: u64,
+    frames: u64,
+    history: VecDeque<Duration>,
+}
+
+/// 判定应用停止绘制后恢复的条件，两个条件都满足时这一帧的帧时间主要是空闲时间
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdleThreshold {
+    /// 帧时间至少是该surface最近帧时间中位数的多少倍
+    pub ratio: f64,
+    /// 帧时间的绝对下限，避免把严重的卡顿当成空闲
+    pub min: Duration,
+}
+
+impl Default for IdleThreshold {
+    fn default() -> Self {
+        Self {
+            ratio: 10.0,
+            min: Duration::from_millis(500),
+        }
+    }
+}
+
+impl IdleThreshold {
+    // history是这一帧之前的帧时间，太短时无法判断节奏
+    fn is_idle(&self, frametime: Duration, history: &VecDeque<Duration>) -> bool {
+        if frametime <= self.min || history.len() < 3 {
+            return false;
+        }
+
+        let mut sorted: Vec<_> = history.iter().copied().collect();
+        sorted.sort_unstable();
+        let pacing = sorted[sorted.len() / 2];
+        frametime.as_secs_f64() > pacing.as_secs_f64() * self.ratio
+    }
+}
+
+/// 一个surface上的一帧
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SurfaceFrame {
+    pub frametime: Duration,
+    /// 内核在这一帧之前丢弃过该surface的帧
+    pub possibly_merged: bool,
+    /// 应用停止绘制一段时间后恢复的第一帧
+    pub resumed_after_idle: bool,
+    /// 该surface在这一帧之后是否被判定为主surface
+    pub is_main: bool,
+}
+
+/// 应用中一个surface的概况
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceInfo {
+    pub id: SurfaceId,
+    /// 开始监控以来该surface提交的帧数
+    pub frames: u64,
+    /// 按最近的帧时间历史计算的帧率，历史为空时为0
+    pub recent_fps: f64,
+    /// 最后一次提交帧的`CLOCK_MONOTONIC`时间戳(ns)
+    pub last_ktime_ns: u64,
+    pub is_main: bool,
 }
 
 impl AnalyzeTarget {
-    pub fn new(uprobe: UprobeHandler) -> Self {
+    pub fn new(history_len: usize, idle: IdleThreshold) -> Self {
         Self {
-            uprobe,
+            history_len: history_len.max(1),
+            idle,
             buffers: HashMap::new(),
+            selector: None,
         }
     }
 
-    pub fn update(&mut self) -> Option<Duration> {
-        let mut ring = self.uprobe.ring().unwrap();
-        let item = ring.next()?;
-        let event = unsafe { trans(&item) };
-        if let Some((timestamp, buffer)) = self.buffers.get_mut(&event.buffer) {
-            let frametime = event.ktime_ns.saturating_sub(*timestamp);
-            *timestamp = event.ktime_ns;
+    pub fn set_selector(&mut self, selector: Option<Box<dyn SurfaceSelector>>) {
+        self.selector = selector;
+    }
 
-            if buffer.len() >= 144 {
-                buffer.pop_back();
-            }
+    /// 记录一帧，surface第一次出现或者事件乱序到达时没有帧时间，返回None
+    pub fn update(
+        &mut self,
+        event: &FrameSignal,
+        default: &dyn SurfaceSelector,
+    ) -> Option<SurfaceFrame> {
+        let Some(surface) = self.buffers.get_mut(&event.buffer) else {
+            self.buffers.insert(
+                event.buffer,
+                Surface {
+                    timestamp: event.ktime_ns,
+                    frames: 1,
+                    history: VecDeque::with_capacity(self.history_len),
+                },
+            );
+            return None;
+        };
 
-            buffer.push_front(Duration::from_nanos(frametime));
-        } else {
-            self.buffers
-                .insert(event.buffer, (event.ktime_ns, VecDeque::with_capacity(144)));
+        // 比上一帧还早的事件是乱序到达的，计入会让帧时间和时间戳都出错
+        if event.ktime_ns < surface.timestamp {
+            return None;
         }
 
-        let max_len = self
-            .buffers
-            .values()
-            .map(|(_, buffer)| buffer.len())
-            .max()
-            .unwrap_or_default();
-        if self.buffers.get(&event.buffer)
-            == self
-                .buffers
-                .values()
-                .filter(|(_, buffer)| buffer.len() == max_len)
-                .min_by_key(|(_, buffer)| buffer.iter().copied().sum::<Duration>())
-        {
-            self.buffers.get(&event.buffer)?.1.front().copied()
-        } else {
-            None
+        let frametime = Duration::from_nanos(event.ktime_ns - surface.timestamp);
+        surface.timestamp = event.ktime_ns;
+        surface.frames += 1;
+
+        // 空闲时间不是帧时间，记入历史会让主surface的选择发生跳变
+        let resumed_after_idle = self.idle.is_idle(frametime, &surface.history);
+        if !resumed_after_idle {
+            if surface.history.len() >= self.history_len {
+                surface.history.pop_back();
+            }
+
+            surface.history.push_front(frametime);
         }
+
+        Some(SurfaceFrame {
+            frametime,
+            possibly_merged: event.is_merged(),
+            resumed_after_idle,
+            is_main: self.main_surface(default) == Some(event.buffer),
+        })
+    }
+
+    pub fn surfaces(&self, default: &dyn SurfaceSelector) -> Vec<SurfaceInfo> {
+        let main = self.main_surface(default);
+        self.views()
+            .map(|view| SurfaceInfo {
+                id: view.id(),
+                frames: view.frames(),
+                recent_fps: view.recent_fps(),
+                last_ktime_ns: view.last_ktime_ns(),
+                is_main: main == Some(view.id()),
+            })
+            .collect()
+    }
+
+    fn main_surface(&self, default: &dyn SurfaceSelector) -> Option<SurfaceId> {
+        let views: Vec<_> = self.views().collect();
+        self.selector.as_deref().unwrap_or(default).select(&views)
+    }
+
+    fn views(&self) -> impl Iterator<Item = SurfaceView<'_>> {
+        self.buffers.iter().map(|(id, surface)| {
+            SurfaceView::new(*id, surface.frames, surface.timestamp, &surface.history)
+        })
     }
 }
 
-const unsafe fn trans(buf: &[u8]) -> FrameSignal {
-    unsafe { ptr::read_unaligned(buf.as_ptr().cast::<FrameSignal>()) }
+// 长度或版本不符的条目来自布局不同的eBPF程序，直接丢弃
+pub const fn trans(buf: &[u8]) -> Option<FrameSignal> {
+    if buf.len() < mem::size_of::<FrameSignal>() {
+        return None;
+    }
+
+    let signal = unsafe { ptr::read_unaligned(buf.as_ptr().cast::<FrameSignal>()) };
+    if signal.is_current_version() {
+        Some(signal)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selector::LongestHistory;
+
+    #[test]
+    fn idle_gap_is_flagged() {
+        let mut target = AnalyzeTarget::new(144, IdleThreshold::default());
+        let mut ktime_ns = 0;
+        let mut frame = |frametime: Duration| {
+            ktime_ns += frametime.as_nanos() as u64;
+            target.update(&FrameSignal::new(ktime_ns, 1, 1, 1), &LongestHistory)
+        };
+
+        assert!(frame(Duration::ZERO).is_none());
+        // 历史太短时不判定
+        assert!(!frame(Duration::from_secs(2)).unwrap().resumed_after_idle);
+
+        for _ in 0..10 {
+            assert!(!frame(Duration::from_millis(16)).unwrap().resumed_after_idle);
+        }
+        // 超过中位数10倍但不到500ms，是卡顿而不是空闲
+        assert!(
+            !frame(Duration::from_millis(400))
+                .unwrap()
+                .resumed_after_idle
+        );
+        assert!(frame(Duration::from_secs(3)).unwrap().resumed_after_idle);
+        assert!(!frame(Duration::from_millis(16)).unwrap().resumed_after_idle);
+    }
+
+    #[test]
+    fn out_of_order_signal_is_ignored() {
+        let mut target = AnalyzeTarget::new(144, IdleThreshold::default());
+        let mut frame = |ktime_ms: u64| {
+            target
+                .update(&FrameSignal::new(ktime_ms * 1_000_000, 1, 1, 1), &LongestHistory)
+                .map(|frame| frame.frametime)
+        };
+
+        assert_eq!(frame(0), None);
+        assert_eq!(frame(32), Some(Duration::from_millis(32)));
+        assert_eq!(frame(16), None);
+        assert_eq!(frame(48), Some(Duration::from_millis(16)));
+    }
+
+    #[test]
+    fn idle_gap_keeps_main_surface() {
+        let mut target = AnalyzeTarget::new(10, IdleThreshold::default());
+        let mut frame = |buffer, ktime_ms: u64| {
+            target.update(&FrameSignal::new(ktime_ms * 1_000_000, buffer, 1, 1), &LongestHistory)
+        };
+
+        // 两个surface的历史一样长，帧时间总和较小的0x1是主surface
+        for i in 0..=10 {
+            frame(0x1, i * 16);
+            frame(0x2, i * 17);
+        }
+        assert!(frame(0x1, 11 * 16).unwrap().is_main);
+
+        let resumed = frame(0x1, 11 * 16 + 3000).unwrap();
+        assert!(resumed.resumed_after_idle);
+        assert_eq!(resumed.frametime, Duration::from_secs(3));
+        assert!(resumed.is_main);
+        assert!(!frame(0x2, 11 * 17).unwrap().is_main);
+    }
 }
@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    future,
+    ops::{Deref, DerefMut},
+    os::unix::io::{AsRawFd, RawFd},
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use futures_core::Stream;
+use tokio::{
+    io::{Interest, unix::AsyncFd},
+    time::{Interval, MissedTickBehavior},
+};
+
+use crate::{Analyzer, AnalyzerEvent, FrameEvent, RESCAN_INTERVAL, error::Result};
+
+// 只借用Analyzer内部poll的fd，fd的所有权仍在Analyzer中
+struct PollFd(RawFd);
+
+impl AsRawFd for PollFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// 在tokio中使用的[`Analyzer`]，同时也是[`FrameEvent`]的[`Stream`]
+///
+/// 通过`Deref`可以在流仍在使用时附加、解除应用
+pub struct AsyncAnalyzer {
+    // 必须先于analyzer被drop，以便在fd关闭前从tokio中注销
+    fd: AsyncFd<PollFd>,
+    // 等待跟随的进程重启时没有fd会就绪，靠定时器驱动扫描
+    rescan: Interval,
+    analyzer: Analyzer,
+}
+
+impl AsyncAnalyzer {
+    /// 必须在启用了time的tokio运行时中调用
+    ///
+    /// # Errors
+    ///
+    /// 向tokio注册fd失败
+    ///
+    /// # Panics
+    ///
+    /// tokio运行时没有启用time
+    pub fn new(analyzer: Analyzer) -> Result<Self> {
+        let fd = AsyncFd::with_interest(PollFd(analyzer.poll.as_raw_fd()), Interest::READABLE)?;
+        let mut rescan = tokio::time::interval(RESCAN_INTERVAL);
+        rescan.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ok(Self {
+            fd,
+            rescan,
+            analyzer,
+        })
+    }
+
+    #[must_use]
+    pub fn into_inner(self) -> Analyzer {
+        self.analyzer
+    }
+
+    /// 等待下一帧
+    ///
+    /// 取消安全：未返回的帧留在内部队列中，下次调用时返回
+    pub async fn recv_frame(&mut self) -> Option<FrameEvent> {
+        future::poll_fn(|cx| self.poll_recv_frame(cx)).await
+    }
+
+    /// 等待下一个事件，与[`AsyncAnalyzer::recv_frame`]一样是取消安全的
+    pub async fn recv_event(&mut self) -> Option<AnalyzerEvent> {
+        future::poll_fn(|cx| self.poll_recv_event(cx)).await
+    }
+
+    /// 跳过帧以外的事件，注册fd出错时返回`Ready(None)`
+    pub fn poll_recv_frame(&mut self, cx: &mut Context<'_>) -> Poll<Option<FrameEvent>> {
+        loop {
+            let Some(event) = ready!(self.poll_recv_event(cx)) else {
+                return Poll::Ready(None);
+            };
+
+            if let Some(event) = event.frame() {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+
+    /// 注册fd出错时返回`Ready(None)`
+    pub fn poll_recv_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<AnalyzerEvent>> {
+        loop {
+            if let Some(event) = self.analyzer.try_recv_inner() {
+                return Poll::Ready(Some(event));
+            }
+
+            // 定时器到期后重新扫描，try_recv_inner自己会限制扫描频率
+            if self.analyzer.waiting_restart() && self.rescan.poll_tick(cx).is_ready() {
+                continue;
+            }
+
+            let Ok(mut guard) = ready!(self.fd.poll_read_ready(cx)) else {
+                return Poll::Ready(None);
+            };
+
+            // 取走内部poll上的就绪事件，之后再读ring，不会漏掉清除就绪状态前到达的数据
+            self.analyzer.wait(Some(Duration::ZERO));
+            guard.clear_ready();
+        }
+    }
+}
+
+impl Deref for AsyncAnalyzer {
+    type Target = Analyzer;
+
+    fn deref(&self) -> &Self::Target {
+        &self.analyzer
+    }
+}
+
+impl DerefMut for AsyncAnalyzer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.analyzer
+    }
+}
+
+impl Stream for AsyncAnalyzer {
+    type Item = FrameEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv_frame(cx)
+    }
+}
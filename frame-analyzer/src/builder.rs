@@ -0,0 +1,209 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::path::PathBuf;
+
+use crate::{
+    Analyzer, AttachMode, IdleThreshold, ProbeScope, SurfaceFilter, SurfaceSelector, error::Result,
+    selector::LongestHistory, stats::StatsWindow, target_fps::TargetFpsConfig, uprobe::ProbeConfig,
+};
+
+/// 系统的页大小，部分arm64设备上是16KiB
+pub fn page_size() -> u32 {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    u32::try_from(size).unwrap_or(0x1000)
+}
+
+/// [`Analyzer`]的配置，所有选项都有与[`Analyzer::new`]相同的默认值
+pub struct AnalyzerBuilder {
+    pub(crate) mode: AttachMode,
+    pub(crate) scope: ProbeScope,
+    pub(crate) filter: SurfaceFilter,
+    pub(crate) selector: Box<dyn SurfaceSelector>,
+    pub(crate) history_len: usize,
+    pub(crate) idle_threshold: IdleThreshold,
+    pub(crate) event_batch: usize,
+    pub(crate) stats_window: StatsWindow,
+    pub(crate) target_fps: Option<TargetFpsConfig>,
+    pub(crate) ring_size: u32,
+    pub(crate) probe: ProbeConfig,
+    pub(crate) foreground_procs: PathBuf,
+}
+
+impl Default for AnalyzerBuilder {
+    fn default() -> Self {
+        Self {
+            mode: AttachMode::default(),
+            scope: ProbeScope::default(),
+            filter: SurfaceFilter::default(),
+            selector: Box::new(LongestHistory),
+            history_len: 144,
+            idle_threshold: IdleThreshold::default(),
+            event_batch: 1024,
+            stats_window: StatsWindow::default(),
+            target_fps: None,
+            ring_size: page_size(),
+            probe: ProbeConfig::default(),
+            foreground_procs: PathBuf::from("/dev/cpuset/top-app/cgroup.procs"),
+        }
+    }
+}
+
+impl AnalyzerBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub const fn attach_mode(mut self, mode: AttachMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    #[must_use]
+    pub const fn probe_scope(mut self, scope: ProbeScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    #[must_use]
+    pub const fn surface_filter(mut self, filter: SurfaceFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    #[must_use]
+    pub fn surface_selector<S: SurfaceSelector + 'static>(mut self, selector: S) -> Self {
+        self.selector = Box::new(selector);
+        self
+    }
+
+    /// 每个surface保留的帧时间历史长度，默认144，至少为1
+    #[must_use]
+    pub fn history_len(mut self, len: usize) -> Self {
+        self.history_len = len.max(1);
+        self
+    }
+
+    /// 判定[`FrameEvent::resumed_after_idle`](crate::FrameEvent::resumed_after_idle)的条件，
+    /// 默认超过最近帧时间中位数的10倍并且超过500ms
+    #[must_use]
+    pub const fn idle_threshold(mut self, threshold: IdleThreshold) -> Self {
+        self.idle_threshold = threshold;
+        self
+    }
+
+    /// 每次poll最多处理的事件数，默认1024，至少为1
+    #[must_use]
+    pub fn event_batch(mut self, batch: usize) -> Self {
+        self.event_batch = batch.max(1);
+        self
+    }
+
+    /// [`Analyzer::frame_stats`]的滚动窗口，默认最近120帧
+    #[must_use]
+    pub const fn stats_window(mut self, window: StatsWindow) -> Self {
+        self.stats_window = window;
+        self
+    }
+
+    /// 推断每个surface的目标帧率，变化时产生[`AnalyzerEvent::TargetFpsChanged`](crate::AnalyzerEvent::TargetFpsChanged)，默认关闭
+    ///
+    /// 开启后[`Analyzer::recv`]等只接收帧的接口会在收到该事件时返回None
+    #[must_use]
+    pub fn target_fps(mut self, config: TargetFpsConfig) -> Self {
+        self.target_fps = Some(config);
+        self
+    }
+
+    /// 内核ring buffer的字节数，默认一页
+    ///
+    /// 内核要求大小是页大小的2的幂次倍，不满足时向上取整。
+    /// 没有ring buffer的旧内核上，这是每个cpu的perf buffer大小
+    #[must_use]
+    pub fn ring_size(mut self, bytes: u32) -> Self {
+        self.ring_size = bytes
+            .max(page_size())
+            .checked_next_power_of_two()
+            .unwrap_or(1 << 31);
+        self
+    }
+
+    /// 按顺序尝试的libgui.so路径，替换默认列表
+    #[must_use]
+    pub fn libgui_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.probe.libgui_paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 库的符号表中找不到queueBuffer时，按顺序尝试的符号，替换默认列表
+    #[must_use]
+    pub fn symbols<I, S>(mut self, symbols: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.probe.symbols = symbols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 按进程名查找应用、解析应用映射的库时使用的目录，默认`/proc`
+    #[must_use]
+    pub fn proc_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.probe.proc_root = root.into();
+        self
+    }
+
+    /// 跟随前台应用时监听的进程列表文件，默认`/dev/cpuset/top-app/cgroup.procs`
+    #[must_use]
+    pub fn foreground_procs<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.foreground_procs = path.into();
+        self
+    }
+
+    /// # Errors
+    ///
+    /// 创建内部的poll实例失败
+    pub fn build(self) -> Result<Analyzer> {
+        Analyzer::from_builder(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_size_is_rounded_to_page_power_of_two() {
+        let size = |bytes| AnalyzerBuilder::new().ring_size(bytes).ring_size;
+        let page = page_size();
+
+        assert!(page.is_power_of_two());
+        assert_eq!(AnalyzerBuilder::new().ring_size, page);
+        assert_eq!(size(0), page);
+        assert_eq!(size(page), page);
+        assert_eq!(size(page + 1), page * 2);
+        assert_eq!(size(page * 24), page * 32);
+        assert_eq!(size(u32::MAX), 1 << 31);
+    }
+}
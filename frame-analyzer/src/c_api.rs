@@ -17,7 +17,7 @@
 use std::{
     sync::{
         Arc, Mutex, Condvar, LazyLock,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
     time::Duration,
     os::unix::io::RawFd,
@@ -26,7 +26,7 @@ use std::{
     panic::{catch_unwind, AssertUnwindSafe},
 };
 use libc::{c_int, c_uint, c_void, eventfd, EFD_NONBLOCK, EFD_CLOEXEC, write, close, read};
-use crate::{Analyzer, Pid};
+use crate::{Analyzer, AnalyzerEvent, AnalyzerWaker, AttachMode, Pid};
 
 /// 帧数据缓冲区：分离监听与读取逻辑，避免锁竞争
 struct FrameBuffer {
@@ -48,8 +48,7 @@ impl FrameBuffer {
         if !self.running.load(Ordering::Acquire) {
             return;
         }
-        let mut data = self.data.lock().unwrap();
-        data.push_back((pid, frametime));
+        self.data.lock().unwrap().push_back((pid, frametime));
         self.cond.notify_one();
     }
 
@@ -57,9 +56,10 @@ impl FrameBuffer {
         if !self.running.load(Ordering::Acquire) {
             return None;
         }
-        // 修复：移除多余的 mut
-        let data = self.data.lock().unwrap();
-        let (mut data, _) = self.cond.wait_timeout(data, timeout).unwrap();
+        let (mut data, _) = self
+            .cond
+            .wait_timeout(self.data.lock().unwrap(), timeout)
+            .unwrap();
         data.iter().position(|(p, _)| *p == pid).map(|pos| {
             let (_, ft) = data.remove(pos).unwrap();
             ft
@@ -78,6 +78,10 @@ static GLOBAL_ANALYZER: Mutex<Option<Arc<Mutex<Analyzer>>>> = Mutex::new(None);
 static FRAME_BUFFER: LazyLock<Arc<FrameBuffer>> = LazyLock::new(|| Arc::new(FrameBuffer::new()));
 static NOTIFY_FD: Mutex<Option<RawFd>> = Mutex::new(None);
 static NOTIFY_THREAD: Mutex<Option<thread::JoinHandle<()>>> = Mutex::new(None);
+// 监听线程阻塞在recv上时持有Analyzer的锁，其它操作先唤醒它再取锁
+static WAKER: Mutex<Option<AnalyzerWaker>> = Mutex::new(None);
+// 正在等待Analyzer锁的操作数，不为0时监听线程让出锁
+static CONTROL: AtomicUsize = AtomicUsize::new(0);
 
 // 新增：暂停控制相关全局变量
 static PAUSED: AtomicBool = AtomicBool::new(false);
@@ -94,10 +98,14 @@ pub struct FrameTime {
 /// 简化eventfd读取：仅清空一次，无循环无返回值
 fn read_eventfd(fd: RawFd) {
     let mut val = 0u64;
-    unsafe { read(fd, &mut val as *mut u64 as *mut c_void, 8) };
+    unsafe { read(fd, (&raw mut val).cast::<c_void>(), 8) };
 }
 
 /// 初始化EBPF和全局资源
+///
+/// # Panics
+///
+/// 内部的锁中毒时panic，跨越C边界时进程会abort
 #[unsafe(no_mangle)]
 pub extern "C" fn frame_analyzer_init() -> c_int {
     if RUNNING.load(Ordering::Acquire) {
@@ -109,11 +117,12 @@ pub extern "C" fn frame_analyzer_init() -> c_int {
         return 0;
     }
 
-    // 初始化Analyzer
-    let analyzer = match catch_unwind(|| Analyzer::new()) {
-        Ok(Ok(a)) => a,
-        _ => return -1,
+    // 初始化Analyzer，C接口保持只监控最新附加应用的行为
+    let Ok(Ok(mut analyzer)) = catch_unwind(Analyzer::new) else {
+        return -1;
     };
+    analyzer.set_attach_mode(AttachMode::Single);
+    let waker = analyzer.waker();
 
     // 创建eventfd
     let efd = unsafe { eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC) };
@@ -130,93 +139,101 @@ pub extern "C" fn frame_analyzer_init() -> c_int {
     // 启动后台监听线程（改造后支持暂停）
     let thread = thread::spawn(move || {
         while RUNNING.load(Ordering::Acquire) {
-            // 新增：检查暂停标记，若暂停则阻塞等待（修复let_underscore_lock错误）
-            let pause_guard = PAUSE_MTX.lock().unwrap();
-            let paused_guard = PAUSE_COND.wait_while(pause_guard, |_guard| {
-                PAUSED.load(Ordering::Acquire) && RUNNING.load(Ordering::Acquire)
+            // 暂停或者有操作在等待Analyzer锁时阻塞等待
+            let guard = PAUSE_MTX.lock().unwrap();
+            let guard = PAUSE_COND.wait_while(guard, |_guard| {
+                (PAUSED.load(Ordering::Acquire) || CONTROL.load(Ordering::Acquire) > 0)
+                    && RUNNING.load(Ordering::Acquire)
             }).unwrap();
-            drop(paused_guard); // 显式释放guard（可选）
+            drop(guard);
 
             // 若此时已停止，直接退出循环
             if !RUNNING.load(Ordering::Acquire) {
                 break;
             }
 
-            // 原有逻辑：获取Analyzer锁并读取帧数据
-            let mut analyzer = match analyzer_clone.try_lock() {
-                Ok(a) => a,
-                Err(_) => {
-                    thread::sleep(Duration::from_millis(10));
-                    continue;
-                }
-            };
-
-            let result = catch_unwind(AssertUnwindSafe(|| analyzer.recv_timeout(Duration::from_millis(1))));
+            // 阻塞到有帧或者被唤醒，不再轮询
+            let mut analyzer = analyzer_clone.lock().unwrap();
+            let result = catch_unwind(AssertUnwindSafe(|| analyzer.recv_event()));
             drop(analyzer); // 立即释放锁
 
             match result {
-                Ok(Some((pid, ft))) => {
-                    buffer_clone.push(pid, ft);
+                Ok(Some(AnalyzerEvent::Frame(event))) => {
+                    buffer_clone.push(event.pid, event.frametime);
                     let val: u64 = 1;
-                    unsafe { write(efd_clone, &val as *const u64 as *const c_void, 8) };
+                    unsafe { write(efd_clone, (&raw const val).cast::<c_void>(), 8) };
                 }
-                Ok(None) => thread::sleep(Duration::from_millis(1)),
-                Err(_) => break,
+                Ok(Some(_)) => (),
+                // 只在destroy时退出，其它情况下短暂等待后继续接收，避免出错时空转
+                Ok(None) | Err(_) => thread::sleep(Duration::from_millis(10)),
             }
         }
 
         unsafe { close(efd_clone) };
     });
 
-    // 初始化全局资源
-    *global = Some(analyzer_arc);
+    // 初始化全局资源，最后才发布Analyzer
     *NOTIFY_FD.lock().unwrap() = Some(efd);
     *NOTIFY_THREAD.lock().unwrap() = Some(thread);
+    *WAKER.lock().unwrap() = Some(waker);
+    *global = Some(analyzer_arc);
+    drop(global);
     RUNNING.store(true, Ordering::Release);
 
     0
 }
 
-/// 绑定目标PID
-#[unsafe(no_mangle)]
-pub extern "C" fn frame_analyzer_attach(pid: c_int) -> c_int {
+fn wake_listener() {
+    if let Some(waker) = WAKER.lock().unwrap().as_ref() {
+        let _ = waker.wake();
+    }
+}
+
+/// 唤醒监听线程让出Analyzer锁后执行操作，成功返回0
+fn with_analyzer<T>(f: impl FnOnce(&mut Analyzer) -> crate::error::Result<T>) -> c_int {
     if !RUNNING.load(Ordering::Acquire) {
         return -1;
     }
 
-    let global = GLOBAL_ANALYZER.lock().unwrap();
-    let analyzer = match global.as_ref() {
-        Some(a) => a,
-        None => return -1,
+    let Some(analyzer) = GLOBAL_ANALYZER.lock().unwrap().clone() else {
+        return -1;
     };
 
-    // 带超时重试的锁获取
-    let mut analyzer_lock = None;
-    for _ in 0..50 {
-        match analyzer.try_lock() {
-            Ok(lock) => {
-                analyzer_lock = Some(lock);
-                break;
-            }
-            Err(_) => thread::sleep(Duration::from_millis(10)),
-        }
-    }
+    CONTROL.fetch_add(1, Ordering::AcqRel);
+    wake_listener();
 
-    let mut analyzer = match analyzer_lock {
-        Some(l) => l,
-        None => return -1,
-    };
+    let mut analyzer = analyzer.lock().unwrap();
+    let result = catch_unwind(AssertUnwindSafe(|| f(&mut analyzer)));
+    drop(analyzer);
 
-    let pid = pid as Pid;
-    match catch_unwind(AssertUnwindSafe(|| analyzer.attach_app(pid))) {
-        Ok(Ok(())) => 0,
+    CONTROL.fetch_sub(1, Ordering::AcqRel);
+    let guard = PAUSE_MTX.lock().unwrap();
+    PAUSE_COND.notify_all();
+    drop(guard);
+
+    match result {
+        Ok(Ok(_)) => 0,
         _ => -1,
     }
 }
 
+/// 绑定目标PID
+#[unsafe(no_mangle)]
+pub extern "C" fn frame_analyzer_attach(pid: c_int) -> c_int {
+    with_analyzer(|analyzer| analyzer.attach_app(pid as Pid))
+}
+
 /// 获取帧时间数据
+///
+/// # Safety
+///
+/// `out_frametime`必须为空指针，或者指向一个可写的[`FrameTime`]
+///
+/// # Panics
+///
+/// 内部的锁中毒时panic，跨越C边界时进程会abort
 #[unsafe(no_mangle)]
-pub extern "C" fn frame_analyzer_get_frametime(
+pub unsafe extern "C" fn frame_analyzer_get_frametime(
     pid: c_int,
     timeout_ms: c_int,
     out_frametime: *mut FrameTime,
@@ -234,60 +251,32 @@ pub extern "C" fn frame_analyzer_get_frametime(
     });
 
     // 清空eventfd
-    if let Some(fd) = *NOTIFY_FD.lock().unwrap() {
+    let notify_fd = *NOTIFY_FD.lock().unwrap();
+    if let Some(fd) = notify_fd {
         read_eventfd(fd);
     }
 
-    match FRAME_BUFFER.pop(pid, timeout) {
-        Some(frametime) => {
-            let ft = FrameTime {
-                secs: frametime.as_secs() as c_uint,
-                nanos: frametime.subsec_nanos() as c_uint,
-            };
-            unsafe { *out_frametime = ft; }
-            0
-        }
-        None => -1,
-    }
+    FRAME_BUFFER.pop(pid, timeout).map_or(-1, |frametime| {
+        let ft = FrameTime {
+            secs: frametime.as_secs() as c_uint,
+            nanos: frametime.subsec_nanos() as c_uint,
+        };
+        unsafe { *out_frametime = ft; }
+        0
+    })
 }
 
 /// 解绑PID
 #[unsafe(no_mangle)]
 pub extern "C" fn frame_analyzer_detach(pid: c_int) -> c_int {
-    if !RUNNING.load(Ordering::Acquire) {
-        return -1;
-    }
-
-    let global = GLOBAL_ANALYZER.lock().unwrap();
-    let analyzer = match global.as_ref() {
-        Some(a) => a,
-        None => return -1,
-    };
-
-    let mut analyzer_lock = None;
-    for _ in 0..50 {
-        match analyzer.try_lock() {
-            Ok(lock) => {
-                analyzer_lock = Some(lock);
-                break;
-            }
-            Err(_) => thread::sleep(Duration::from_millis(10)),
-        }
-    }
-
-    let mut analyzer = match analyzer_lock {
-        Some(l) => l,
-        None => return -1,
-    };
-
-    let pid = pid as Pid;
-    match catch_unwind(AssertUnwindSafe(|| analyzer.detach_app(pid))) {
-        Ok(Ok(())) => 0,
-        _ => -1,
-    }
+    with_analyzer(|analyzer| analyzer.detach_app(pid as Pid))
 }
 
 /// 销毁资源
+///
+/// # Panics
+///
+/// 内部的锁中毒时panic，跨越C边界时进程会abort
 #[unsafe(no_mangle)]
 pub extern "C" fn frame_analyzer_destroy() -> c_int {
     if !RUNNING.load(Ordering::Acquire) {
@@ -300,32 +289,37 @@ pub extern "C" fn frame_analyzer_destroy() -> c_int {
 
     RUNNING.store(false, Ordering::Release);
     FRAME_BUFFER.stop();
+    wake_listener();
 
     // 等待监听线程退出
-    if let Some(thread) = NOTIFY_THREAD.lock().unwrap().take() {
+    let thread = NOTIFY_THREAD.lock().unwrap().take();
+    if let Some(thread) = thread {
         thread.join().ok();
     }
 
     // 清理Analyzer资源
-    let mut global = GLOBAL_ANALYZER.lock().unwrap();
-    if let Some(analyzer) = global.as_ref() {
-        if let Ok(mut analyzer) = analyzer.try_lock() {
-            let _ = catch_unwind(AssertUnwindSafe(|| analyzer.detach_apps()));
-        }
+    let global = GLOBAL_ANALYZER.lock().unwrap().take();
+    if let Some(analyzer) = global
+        && let Ok(mut analyzer) = analyzer.try_lock()
+    {
+        let _ = catch_unwind(AssertUnwindSafe(|| analyzer.detach_apps()));
     }
-    *global = None;
+    *WAKER.lock().unwrap() = None;
 
     // 关闭eventfd
-    let mut notify_fd = NOTIFY_FD.lock().unwrap();
-    if let Some(fd) = *notify_fd {
+    let notify_fd = NOTIFY_FD.lock().unwrap().take();
+    if let Some(fd) = notify_fd {
         unsafe { close(fd); }
     }
-    *notify_fd = None;
 
     0
 }
 
 /// 获取通知FD
+///
+/// # Panics
+///
+/// 内部的锁中毒时panic，跨越C边界时进程会abort
 #[unsafe(no_mangle)]
 pub extern "C" fn frame_analyzer_get_notify_fd() -> c_int {
     if !RUNNING.load(Ordering::Acquire) {
@@ -344,6 +338,7 @@ pub extern "C" fn frame_analyzer_pause() -> c_int {
     }
 
     PAUSED.store(true, Ordering::Release);
+    wake_listener(); // 让阻塞中的监听线程进入暂停
     0 // 成功暂停返回0
 }
 
@@ -362,9 +357,6 @@ pub extern "C" fn frame_analyzer_resume() -> c_int {
 // 新增：查询暂停状态（C接口）
 #[unsafe(no_mangle)]
 pub extern "C" fn frame_analyzer_is_paused() -> c_int {
-    if PAUSED.load(Ordering::Acquire) {
-        1 // 暂停中返回1
-    } else {
-        0 // 运行中返回0
-    }
+    // 暂停中返回1，运行中返回0
+    c_int::from(PAUSED.load(Ordering::Acquire))
 }
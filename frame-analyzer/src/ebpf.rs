@@ -15,9 +15,58 @@
 * along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use aya::{Ebpf, include_bytes_aligned};
+use std::os::fd::AsRawFd;
+
+use aya::{
+    Ebpf, EbpfLoader, include_bytes_aligned,
+    maps::{
+        HashMap, Map, MapData, MapError, PerCpuArray, PerfEventArray, RingBuf,
+        perf::PerfEventArrayBuffer,
+    },
+    programs::UProbe,
+    util::online_cpus,
+};
+use bytes::BytesMut;
 use ctor::ctor;
-use crate::error::Result;
+use frame_analyzer_ebpf_common::FrameSignal;
+use mio::{Interest, Registry, Token, unix::SourceFd};
+
+use crate::{
+    Pid,
+    analyze_target::trans,
+    builder::page_size,
+    error::{AnalyzerError, Result},
+    preflight::ringbuf_supported,
+};
+
+pub const PROGRAM: &str = "frame_analyzer_ebpf";
+const RING_BUF: &str = "RING_BUF";
+const PERF_EVENTS: &str = "PERF_EVENTS";
+const PID_FILTER: &str = "PID_FILTER";
+const LOST_EVENTS: &str = "LOST_EVENTS";
+
+// 每次从perf buffer中读取的事件数
+const PERF_READ_BATCH: usize = 16;
+
+macro_rules! ebpf_object {
+    ($name:literal) => {{
+        #[cfg(debug_assertions)]
+        let object = include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/ebpf_target/bpfel-unknown-none/debug/",
+            $name
+        ));
+
+        #[cfg(not(debug_assertions))]
+        let object = include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/ebpf_target/bpfel-unknown-none/release/",
+            $name
+        ));
+
+        object
+    }};
+}
 
 #[ctor]
 fn ebpf_workround() {
@@ -25,21 +74,359 @@ fn ebpf_workround() {
         rlim_cur: libc::RLIM_INFINITY,
         rlim_max: libc::RLIM_INFINITY,
     };
-    unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+    unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &raw const rlim) };
+}
+
+/// eBPF程序的两种变体，除了上报事件的map以外完全相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    /// `BPF_MAP_TYPE_RINGBUF`，需要5.8以上的内核
+    RingBuf,
+    /// `BPF_MAP_TYPE_PERF_EVENT_ARRAY`，每个cpu一个buffer
+    PerfEventArray,
+}
+
+impl Variant {
+    pub fn detect() -> Self {
+        Self::from_probe(ringbuf_supported())
+    }
+
+    /// 无法探测时（例如没有权限）按ring buffer加载，由加载错误说明原因
+    const fn from_probe(ringbuf_supported: Option<bool>) -> Self {
+        match ringbuf_supported {
+            Some(false) => Self::PerfEventArray,
+            Some(true) | None => Self::RingBuf,
+        }
+    }
+
+    const fn object(self) -> &'static [u8] {
+        match self {
+            Self::RingBuf => ebpf_object!("frame-analyzer-ebpf"),
+            Self::PerfEventArray => ebpf_object!("frame-analyzer-ebpf-perf"),
+        }
+    }
+}
+
+/// `RING_BUF`的大小在加载时设置，覆盖eBPF程序中编译期的默认值
+pub fn load_bpf(variant: Variant, ring_size: u32) -> Result<Ebpf> {
+    let mut loader = EbpfLoader::new();
+    if variant == Variant::RingBuf {
+        loader.set_max_entries(RING_BUF, ring_size);
+    }
+
+    loader
+        .load(variant.object())
+        .map_err(AnalyzerError::load)
+}
+
+/// 帧事件的来源，对调用者隐藏两种变体的差别
+enum Events {
+    Ring(RingBuf<MapData>),
+    Perf(PerfBuffers),
+}
+
+struct PerfBuffers {
+    buffers: Vec<PerfEventArrayBuffer<MapData>>,
+    out: Vec<BytesMut>,
+}
+
+impl PerfBuffers {
+    // 每个在线cpu使用与ring buffer同样大小的buffer
+    fn open(map: Map, ring_size: u32) -> Result<Self> {
+        let mut array = PerfEventArray::try_from(map).map_err(map_error(PERF_EVENTS))?;
+        let cpus = online_cpus()
+            .map_err(|(path, source)| AnalyzerError::io_with_path(path.as_ref(), source))?;
+        let page_count = (ring_size / page_size()).max(1) as usize;
+
+        let buffers = cpus
+            .into_iter()
+            .map(|cpu| {
+                array
+                    .open(cpu, Some(page_count))
+                    .map_err(|source| AnalyzerError::PerfBuffer { cpu, source })
+            })
+            .collect::<Result<_>>()?;
+        let out = (0..PERF_READ_BATCH)
+            .map(|_| BytesMut::with_capacity(size_of::<FrameSignal>()))
+            .collect();
+
+        Ok(Self { buffers, out })
+    }
+
+    // 渲染线程可能在cpu之间迁移，逐个cpu读出的事件要按时间重新排序
+    fn drain_into(&mut self, signals: &mut Vec<FrameSignal>) {
+        for buffer in &mut self.buffers {
+            // 丢弃的事件已经由eBPF程序计入LOST_EVENTS，这里不重复统计
+            while let Ok(events) = buffer.read_events(&mut self.out) {
+                signals.extend(self.out[..events.read].iter().filter_map(|buf| trans(buf)));
+                if events.read < self.out.len() {
+                    break;
+                }
+            }
+        }
+        sort_by_time(signals);
+    }
+}
+
+/// 所有被监控应用共享的eBPF对象，只加载一次程序和一组事件buffer
+pub struct FrameBpf {
+    bpf: Ebpf,
+    events: Events,
+    pid_filter: HashMap<MapData, u32, u8>,
+    lost_events: PerCpuArray<MapData, u64>,
 }
 
-pub fn load_bpf() -> Result<Ebpf> {
-    #[cfg(debug_assertions)]
-    let bpf = Ebpf::load(include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/ebpf_target/bpfel-unknown-none/debug/frame-analyzer-ebpf"
-    )))?;
+impl FrameBpf {
+    pub fn load(ring_size: u32) -> Result<Self> {
+        Self::load_variant(Variant::detect(), ring_size)
+    }
+
+    fn load_variant(variant: Variant, ring_size: u32) -> Result<Self> {
+        let mut bpf = load_bpf(variant, ring_size)?;
+        program_mut(&mut bpf)?
+            .load()
+            .map_err(|source| AnalyzerError::Program {
+                name: PROGRAM,
+                source,
+            })?;
+
+        let events = match variant {
+            Variant::RingBuf => Events::Ring(
+                RingBuf::try_from(take_map(&mut bpf, RING_BUF)?).map_err(map_error(RING_BUF))?,
+            ),
+            Variant::PerfEventArray => {
+                Events::Perf(PerfBuffers::open(take_map(&mut bpf, PERF_EVENTS)?, ring_size)?)
+            }
+        };
+        let pid_filter =
+            HashMap::try_from(take_map(&mut bpf, PID_FILTER)?).map_err(map_error(PID_FILTER))?;
+        let lost_events = PerCpuArray::try_from(take_map(&mut bpf, LOST_EVENTS)?)
+            .map_err(map_error(LOST_EVENTS))?;
+
+        Ok(Self {
+            bpf,
+            events,
+            pid_filter,
+            lost_events,
+        })
+    }
+
+    pub fn program(&mut self) -> Result<&mut UProbe> {
+        program_mut(&mut self.bpf)
+    }
+
+    /// 把所有事件buffer的fd以同一个token注册到poll
+    pub fn register(&self, registry: &Registry, token: Token) -> Result<()> {
+        match &self.events {
+            Events::Ring(ring) => {
+                registry.register(&mut SourceFd(&ring.as_raw_fd()), token, Interest::READABLE)?;
+            }
+            Events::Perf(perf) => {
+                for buffer in &perf.buffers {
+                    registry.register(
+                        &mut SourceFd(&buffer.as_raw_fd()),
+                        token,
+                        Interest::READABLE,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 读空所有buffer，跳过版本不匹配的事件
+    pub fn drain(&mut self) -> Vec<FrameSignal> {
+        let mut signals = Vec::new();
+        match &mut self.events {
+            Events::Ring(ring) => {
+                while let Some(item) = ring.next() {
+                    signals.extend(trans(&item));
+                }
+            }
+            Events::Perf(perf) => perf.drain_into(&mut signals),
+        }
+        signals
+    }
+
+    /// 每个cpu上因事件buffer已满丢弃的帧数
+    pub fn lost_events(&self) -> Result<Vec<u64>> {
+        let lost = self.lost_events.get(&0, 0).map_err(map_error(LOST_EVENTS))?;
+        Ok(lost.to_vec())
+    }
+
+    /// 允许内核上报该进程的帧事件
+    pub fn allow(&mut self, pid: Pid) -> Result<()> {
+        self.pid_filter
+            .insert(pid as u32, 1, 0)
+            .map_err(pid_filter_error(pid))
+    }
+
+    /// 在内核中丢弃该进程的帧事件
+    pub fn disallow(&mut self, pid: Pid) -> Result<()> {
+        self.pid_filter
+            .remove(&(pid as u32))
+            .map_err(pid_filter_error(pid))
+    }
+}
+
+fn sort_by_time(signals: &mut [FrameSignal]) {
+    signals.sort_by_key(|signal| signal.ktime_ns);
+}
+
+fn program_mut(bpf: &mut Ebpf) -> Result<&mut UProbe> {
+    bpf.program_mut(PROGRAM)
+        .ok_or(AnalyzerError::ProgramNotFound { name: PROGRAM })?
+        .try_into()
+        .map_err(|source| AnalyzerError::Program {
+            name: PROGRAM,
+            source,
+        })
+}
+
+fn take_map(bpf: &mut Ebpf, name: &'static str) -> Result<Map> {
+    bpf.take_map(name)
+        .ok_or(AnalyzerError::MapNotFound { name })
+}
+
+fn map_error(name: &'static str) -> impl FnOnce(MapError) -> AnalyzerError {
+    move |source| AnalyzerError::Map {
+        name,
+        pid: None,
+        source,
+    }
+}
+
+fn pid_filter_error(pid: Pid) -> impl FnOnce(MapError) -> AnalyzerError {
+    move |source| AnalyzerError::Map {
+        name: PID_FILTER,
+        pid: Some(pid),
+        source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use object::{Object, ObjectSection, ObjectSymbol};
+
+    use super::*;
+    use crate::{IdleThreshold, analyze_target::AnalyzeTarget, selector::LongestHistory};
+
+    // 嵌入的eBPF文件中maps段和程序段里的符号
+    fn symbols(variant: Variant, section: &str) -> Vec<String> {
+        let file = object::File::parse(variant.object()).unwrap();
+        file.symbols()
+            .filter(|symbol| {
+                symbol
+                    .section_index()
+                    .and_then(|index| file.section_by_index(index).ok())
+                    .and_then(|found| found.name().ok())
+                    == Some(section)
+            })
+            .filter_map(|symbol| symbol.name().ok().map(str::to_owned))
+            .collect()
+    }
+
+    // 代替`queueBuffer`被uprobe挂载的函数，参数即surface
+    #[unsafe(no_mangle)]
+    #[inline(never)]
+    extern "C" fn frame_analyzer_test_decode(surface: u64) -> u64 {
+        std::hint::black_box(surface)
+    }
+
+    #[unsafe(no_mangle)]
+    #[inline(never)]
+    extern "C" fn frame_analyzer_test_perf(surface: u64) -> u64 {
+        std::hint::black_box(surface)
+    }
+
+    // 需要加载eBPF的权限，调用的测试都标记为ignore
+    fn attach(variant: Variant, symbol: &str) -> FrameBpf {
+        let mut bpf = FrameBpf::load_variant(variant, page_size()).unwrap();
+        bpf.program()
+            .unwrap()
+            .attach(Some(symbol), 0, "/proc/self/exe", None)
+            .unwrap();
+        bpf.allow(std::process::id() as Pid).unwrap();
+        bpf
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn kernel_record_matches_frame_signal() {
+        let mut bpf = attach(Variant::RingBuf, "frame_analyzer_test_decode");
+
+        frame_analyzer_test_decode(0x1234);
+        let signals = bpf.drain();
+        assert_eq!(signals.len(), 1);
+        assert_eq!(signals[0].buffer, 0x1234);
+        assert_eq!(signals[0].tgid, std::process::id());
+        assert_eq!(signals[0].tid, unsafe { libc::gettid() } as u32);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn perf_buffers_are_drained() {
+        let mut bpf = attach(Variant::PerfEventArray, "frame_analyzer_test_perf");
+
+        // 超过一次读取的批量，需要多次读取同一个cpu的buffer
+        let count = PERF_READ_BATCH as u64 * 2 + 1;
+        for surface in 0..count {
+            frame_analyzer_test_perf(surface);
+        }
+        let buffers: Vec<_> = bpf.drain().iter().map(|signal| signal.buffer).collect();
+        assert_eq!(buffers, (0..count).collect::<Vec<_>>());
+        assert!(bpf.drain().is_empty());
+    }
+
+    #[test]
+    fn perf_events_from_two_cpus_are_ordered() {
+        let signal = |ktime_ms: u64, cpu| {
+            FrameSignal::new(ktime_ms * 1_000_000, 0x1, 1, 1).with_task(cpu, [0; 16])
+        };
+        // 线程在两个cpu之间交替，逐个cpu读出时时间是交错的
+        let mut signals = vec![signal(0, 0), signal(32, 0), signal(16, 1), signal(48, 1)];
+        sort_by_time(&mut signals);
+
+        let mut target = AnalyzeTarget::new(144, IdleThreshold::default());
+        let frametimes: Vec<_> = signals
+            .iter()
+            .filter_map(|signal| target.update(signal, &LongestHistory))
+            .map(|frame| frame.frametime)
+            .collect();
+        assert_eq!(frametimes, [Duration::from_millis(16); 3]);
+    }
+
+    #[test]
+    fn variant_follows_ringbuf_probe() {
+        assert_eq!(Variant::from_probe(Some(true)), Variant::RingBuf);
+        assert_eq!(Variant::from_probe(Some(false)), Variant::PerfEventArray);
+        assert_eq!(Variant::from_probe(None), Variant::RingBuf);
+    }
 
-    #[cfg(not(debug_assertions))]
-    let bpf = Ebpf::load(include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/ebpf_target/bpfel-unknown-none/release/frame-analyzer-ebpf"
-    )))?;
+    #[test]
+    #[ignore = "needs CAP_BPF and a 5.8+ kernel"]
+    fn ringbuf_kernel_detects_ringbuf() {
+        // 用实际页大小探测，5.8以上的内核不会因为页大小退回perf变体
+        let report = crate::preflight();
+        assert_eq!(report.ringbuf, Some(true));
+        assert!(report.kernel_version >= Some((5, 8, 0)));
+        assert_eq!(Variant::detect(), Variant::RingBuf);
+    }
 
-    Ok(bpf)
+    #[test]
+    fn embedded_objects_have_expected_maps() {
+        for (variant, events) in [
+            (Variant::RingBuf, RING_BUF),
+            (Variant::PerfEventArray, PERF_EVENTS),
+        ] {
+            let maps = symbols(variant, "maps");
+            for name in [events, PID_FILTER, LOST_EVENTS, "MERGED", "LAST_ENTRY"] {
+                assert!(maps.iter().any(|map| map == name), "missing map {name} in {variant:?}");
+            }
+            assert!(symbols(variant, "uprobe").iter().any(|program| program == PROGRAM));
+        }
+    }
 }
@@ -17,38 +17,354 @@
 * along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::io;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
-use aya::{EbpfError, maps::MapError, programs::ProgramError};
+use aya::{
+    EbpfError,
+    maps::{MapError, perf::PerfBufferError},
+    programs::{ProgramError, uprobe::UProbeError},
+};
 use thiserror::Error;
 
+use crate::{
+    Pid,
+    report::AttachReport,
+};
 
 pub type Result<T> = std::result::Result<T, AnalyzerError>;
 
+// 不支持的操作，内核中的ENOTSUPP，不在libc中
+const ENOTSUPP: i32 = 524;
 
+/// 分析器的错误
+///
+/// 以后可能新增变体，需要按类别处理时匹配[`AnalyzerError::kind`]
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum AnalyzerError {
-    /// Aya EBPF 加载/初始化错误
-    #[error("EBPF error: {0}")]
-    EbpfError(#[from] EbpfError),
+    /// 加载eBPF对象（创建map、加载程序）失败
+    ///
+    /// 是否受memlock限制取决于出错时的rlimit，所以在构造时就分类
+    #[error("Failed to load eBPF object: {source}")]
+    Load {
+        #[source]
+        source: EbpfError,
+        kind: ErrorKind,
+    },
 
-    /// EBPF 程序（如UProbe）相关错误
-    #[error("BPF program error: {0}")]
-    BpfProgramError(#[from] ProgramError),
+    /// eBPF对象中没有这个程序
+    #[error("eBPF program `{name}` not found")]
+    ProgramNotFound { name: &'static str },
 
-    /// EBPF Map 操作错误
-    #[error("BPF map error: {0}")]
-    BpfMapError(#[from] MapError),
+    /// eBPF程序相关错误
+    #[error("eBPF program `{name}` error: {source}")]
+    Program {
+        name: &'static str,
+        #[source]
+        source: ProgramError,
+    },
+
+    /// eBPF对象中没有这个map
+    #[error("eBPF map `{name}` not found")]
+    MapNotFound { name: &'static str },
+
+    /// eBPF map操作错误，pid为正在操作的应用
+    #[error("eBPF map `{name}` error{}: {source}", context(*pid, None))]
+    Map {
+        name: &'static str,
+        pid: Option<Pid>,
+        #[source]
+        source: MapError,
+    },
+
+    /// 打开某个cpu上的perf buffer失败
+    #[error("Failed to open perf buffer on cpu {cpu}: {source}")]
+    PerfBuffer {
+        cpu: u32,
+        #[source]
+        source: PerfBufferError,
+    },
+
+    /// 所有候选库和符号都附加失败，报告中记录了每次尝试的原因
+    #[error("Failed to attach uprobe, {0}")]
+    AttachFailed(AttachReport),
+
+    /// 应用没有被监控
+    #[error("Application {pid} is not attached")]
+    NotAttached { pid: Pid },
+
+    /// 找不到该名称的进程
+    #[error("No process named `{name}`")]
+    ProcessNotFound { name: String },
 
     /// IO 操作错误
-    #[error("IO error: {0}")]
-    IOError(#[from] io::Error),
+    #[error("IO error{}: {source}", context(*pid, path.as_deref()))]
+    Io {
+        pid: Option<Pid>,
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// 错误的大致分类，用于决定是否重试、如何提示用户
+///
+/// 同一个错误总是归入同一类；以后新增的类别只会从[`ErrorKind::Other`]中细分出来
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// 权限不足，需要root或者`CAP_BPF`、`CAP_PERFMON`
+    PermissionDenied,
+    /// 内核不支持需要的功能，例如ringbuf或者uprobe
+    KernelUnsupported,
+    /// `RLIMIT_MEMLOCK`太小，无法创建eBPF map
+    MemlockLimit,
+    /// 目标进程已经退出
+    TargetGone,
+    /// 找不到应用、进程、库、符号、程序或者map
+    NotFound,
+    Other,
+}
+
+impl AnalyzerError {
+    pub(crate) fn load(source: EbpfError) -> Self {
+        let kind = ebpf_errno(&source)
+            .map_or(ErrorKind::Other, |errno| load_kind(errno, memlock_limited()));
+        Self::Load { source, kind }
+    }
+
+    pub(crate) const fn io_with_pid(pid: Pid, source: io::Error) -> Self {
+        Self::Io {
+            pid: Some(pid),
+            path: None,
+            source,
+        }
+    }
+
+    pub(crate) fn io_with_path(path: &Path, source: io::Error) -> Self {
+        Self::Io {
+            pid: None,
+            path: Some(path.to_path_buf()),
+            source,
+        }
+    }
+
+    /// 把底层的errno归类
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ProgramNotFound { .. }
+            | Self::MapNotFound { .. }
+            | Self::NotAttached { .. }
+            | Self::ProcessNotFound { .. } => ErrorKind::NotFound,
+            Self::AttachFailed(report) => report_kind(report),
+            Self::Load { kind, .. } => *kind,
+            Self::Io { pid: Some(_), .. } if self.errno() == Some(libc::ENOENT) => {
+                ErrorKind::TargetGone
+            }
+            _ => self.errno().map_or(ErrorKind::Other, errno_kind),
+        }
+    }
+
+    /// 底层系统调用的errno，没有时为None
+    #[must_use]
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Self::Load { source, .. } => ebpf_errno(source),
+            Self::Program { source, .. } => program_errno(source),
+            Self::Map { source, .. } => map_errno(source),
+            Self::PerfBuffer { source, .. } => perf_buffer_errno(source),
+            Self::Io { source, .. } => source.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// 出错的应用
+    #[must_use]
+    pub const fn pid(&self) -> Option<Pid> {
+        match self {
+            Self::Map { pid, .. } | Self::Io { pid, .. } => *pid,
+            Self::AttachFailed(report) => report.pid,
+            Self::NotAttached { pid } => Some(*pid),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AnalyzerError {
+    fn from(source: io::Error) -> Self {
+        Self::Io {
+            pid: None,
+            path: None,
+            source,
+        }
+    }
+}
+
+fn context(pid: Option<Pid>, path: Option<&Path>) -> String {
+    match (pid, path) {
+        (Some(pid), Some(path)) => format!(" (pid {pid}, {})", path.display()),
+        (Some(pid), None) => format!(" (pid {pid})"),
+        (None, Some(path)) => format!(" ({})", path.display()),
+        (None, None) => String::new(),
+    }
+}
+
+const fn errno_kind(errno: i32) -> ErrorKind {
+    match errno {
+        libc::EPERM | libc::EACCES => ErrorKind::PermissionDenied,
+        libc::ENOSYS | libc::EOPNOTSUPP | ENOTSUPP => ErrorKind::KernelUnsupported,
+        libc::ESRCH => ErrorKind::TargetGone,
+        libc::ENOENT => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    }
+}
+
+// 程序是固定的，加载时内核拒绝(EINVAL)说明缺少需要的map类型或者helper
+// 旧内核用memlock限制eBPF内存，root下创建map返回EPERM通常就是这个原因
+const fn load_kind(errno: i32, memlock_limited: bool) -> ErrorKind {
+    match errno {
+        libc::EINVAL => ErrorKind::KernelUnsupported,
+        libc::EPERM if memlock_limited => ErrorKind::MemlockLimit,
+        _ => errno_kind(errno),
+    }
+}
+
+fn memlock_limited() -> bool {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let limited = unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &raw mut rlim) } == 0
+        && rlim.rlim_cur != libc::RLIM_INFINITY;
+    limited && unsafe { libc::geteuid() } == 0
+}
+
+fn report_kind(report: &AttachReport) -> ErrorKind {
+    let failures = || report.attempts.iter().filter_map(|attempt| attempt.failure.as_ref());
+
+    if failures().any(|failure| failure.kind() == ErrorKind::TargetGone) {
+        ErrorKind::TargetGone
+    } else if failures().any(|failure| failure.kind() == ErrorKind::PermissionDenied) {
+        ErrorKind::PermissionDenied
+    } else if failures().all(|failure| failure.kind() == ErrorKind::NotFound) {
+        ErrorKind::NotFound
+    } else {
+        ErrorKind::Other
+    }
+}
+
+fn ebpf_errno(error: &EbpfError) -> Option<i32> {
+    match error {
+        EbpfError::FileError { error, .. } => error.raw_os_error(),
+        EbpfError::MapError(error) => map_errno(error),
+        EbpfError::ProgramError(error) => program_errno(error),
+        _ => None,
+    }
+}
+
+fn perf_buffer_errno(error: &PerfBufferError) -> Option<i32> {
+    match error {
+        PerfBufferError::OpenError { io_error }
+        | PerfBufferError::MMapError { io_error }
+        | PerfBufferError::PerfEventEnableError { io_error }
+        | PerfBufferError::IOError(io_error) => io_error.raw_os_error(),
+        _ => None,
+    }
+}
+
+fn program_errno(error: &ProgramError) -> Option<i32> {
+    match error {
+        ProgramError::LoadError { io_error, .. }
+        | ProgramError::UProbeError(UProbeError::FileError { io_error, .. }) => {
+            io_error.raw_os_error()
+        }
+        ProgramError::SyscallError(error) => error.io_error.raw_os_error(),
+        ProgramError::MapError(error) => map_errno(error),
+        _ => None,
+    }
+}
+
+fn map_errno(error: &MapError) -> Option<i32> {
+    match error {
+        MapError::CreateError { io_error, .. } | MapError::IoError(io_error) => {
+            io_error.raw_os_error()
+        }
+        MapError::SyscallError(error) => error.io_error.raw_os_error(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aya::sys::SyscallError;
+
+    use super::*;
+    use crate::report::{AttachAttempt, AttachFailure};
+
+    fn syscall(errno: i32) -> SyscallError {
+        SyscallError {
+            call: "bpf_map_update_elem",
+            io_error: io::Error::from_raw_os_error(errno),
+        }
+    }
+
+    #[test]
+    fn errno_is_classified() {
+        let map = |errno| AnalyzerError::Map {
+            name: "PID_FILTER",
+            pid: Some(42),
+            source: MapError::SyscallError(syscall(errno)),
+        };
+        assert_eq!(map(libc::EPERM).kind(), ErrorKind::PermissionDenied);
+        assert_eq!(map(libc::ESRCH).kind(), ErrorKind::TargetGone);
+        assert_eq!(map(libc::ENOSYS).kind(), ErrorKind::KernelUnsupported);
+        assert_eq!(map(libc::E2BIG).kind(), ErrorKind::Other);
+        assert_eq!(map(libc::EPERM).errno(), Some(libc::EPERM));
+        assert_eq!(map(libc::EPERM).pid(), Some(42));
+
+        let load = |errno| {
+            AnalyzerError::load(EbpfError::MapError(MapError::CreateError {
+                name: "RING_BUF".into(),
+                code: -1,
+                io_error: io::Error::from_raw_os_error(errno),
+            }))
+        };
+        assert_eq!(load(libc::EINVAL).kind(), ErrorKind::KernelUnsupported);
+        assert_eq!(load(libc::EINVAL).errno(), Some(libc::EINVAL));
+        assert_eq!(load_kind(libc::EPERM, true), ErrorKind::MemlockLimit);
+        assert_eq!(load_kind(libc::EPERM, false), ErrorKind::PermissionDenied);
+
+        let gone = AnalyzerError::io_with_pid(42, io::Error::from_raw_os_error(libc::ENOENT));
+        assert_eq!(gone.kind(), ErrorKind::TargetGone);
+        assert_eq!(gone.to_string(), format!("IO error (pid 42): {}", io::Error::from_raw_os_error(libc::ENOENT)));
+        assert_eq!(AnalyzerError::NotAttached { pid: 1 }.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn attach_report_is_classified_by_its_failures() {
+        use AttachFailure::{FileMissing, PermissionDenied, PidGone, SymbolMissing};
 
-    /// 目标应用未找到（或附加失败）
-    #[error("Application not found or attach failed")]
-    AppNotFound,
+        let report = |failures: &[AttachFailure]| {
+            let mut report = AttachReport::new(Some(42));
+            report.attempts = failures
+                .iter()
+                .map(|failure| AttachAttempt {
+                    library: "/system/lib64/libgui.so".into(),
+                    symbol: None,
+                    offset: None,
+                    failure: Some(failure.clone()),
+                })
+                .collect();
+            AnalyzerError::AttachFailed(report)
+        };
 
-    /// Map 不存在或获取失败
-    #[error("BPF map not found or invalid")]
-    MapError,
+        assert_eq!(report(&[FileMissing, SymbolMissing]).kind(), ErrorKind::NotFound);
+        assert_eq!(report(&[SymbolMissing, PermissionDenied]).kind(), ErrorKind::PermissionDenied);
+        assert_eq!(report(&[PermissionDenied, PidGone]).kind(), ErrorKind::TargetGone);
+        assert_eq!(report(&[]).kind(), ErrorKind::NotFound);
+    }
 }
@@ -0,0 +1,122 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{borrow::Cow, time::Duration};
+
+use frame_analyzer_ebpf_common::{FrameSignal, TASK_COMM_LEN};
+
+use crate::{Pid, SurfaceId, analyze_target::SurfaceFrame, target_fps::TargetFps};
+
+/// 一帧的完整信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameEvent {
+    /// 应用的进程id
+    pub pid: Pid,
+    /// 提交该帧的surface
+    pub surface: SurfaceId,
+    /// 提交该帧时的`CLOCK_MONOTONIC`时间戳(ns)，可以和trace中的时间直接对齐
+    pub ktime_ns: u64,
+    pub frametime: Duration,
+    /// 该surface的上一帧因ring buffer溢出丢失，帧时间可能覆盖了两帧，统计卡顿时应当忽略
+    pub possibly_merged: bool,
+    /// 应用停止绘制一段时间后恢复的第一帧，帧时间主要是空闲时间，统计时默认忽略
+    pub resumed_after_idle: bool,
+    /// 该surface是否是应用的主surface
+    pub is_main_surface: bool,
+    /// 提交该帧的线程id
+    pub tid: Pid,
+    /// 提交该帧时所在的cpu
+    pub cpu: u32,
+    /// 提交该帧的线程名，原始的内核`comm`
+    pub comm: [u8; TASK_COMM_LEN],
+}
+
+impl FrameEvent {
+    pub(crate) const fn new(signal: &FrameSignal, frame: SurfaceFrame) -> Self {
+        Self {
+            pid: signal.tgid as Pid,
+            surface: signal.buffer,
+            ktime_ns: signal.ktime_ns,
+            frametime: frame.frametime,
+            possibly_merged: frame.possibly_merged,
+            resumed_after_idle: frame.resumed_after_idle,
+            is_main_surface: frame.is_main,
+            tid: signal.tid as Pid,
+            cpu: signal.cpu,
+            comm: signal.comm,
+        }
+    }
+
+    /// 提交该帧的线程名，例如`RenderThread`、`GLThread 123`、`UnityGfxDeviceW`
+    #[must_use]
+    pub fn thread_name(&self) -> Cow<'_, str> {
+        let len = self
+            .comm
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(TASK_COMM_LEN);
+        String::from_utf8_lossy(&self.comm[..len])
+    }
+}
+
+/// 分析器产生的事件
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnalyzerEvent {
+    /// 应用提交了一帧
+    Frame(FrameEvent),
+    /// 阻塞的接收被[`AnalyzerWaker`](crate::AnalyzerWaker)唤醒
+    Woken,
+    /// 被监控的应用已经退出并被自动解除，之后不会再有它的帧
+    TargetExited { pid: Pid },
+    /// 按进程名跟随的应用以新的pid重启，已经自动附加到新进程
+    TargetRestarted { old_pid: Pid, pid: Pid },
+    /// 跟随前台时，应用进入前台并已经被附加
+    ForegroundEntered { pid: Pid },
+    /// 跟随前台时，应用离开前台并已经被解除
+    ForegroundLeft { pid: Pid },
+    /// 推断出的目标帧率发生变化，需要通过[`AnalyzerBuilder::target_fps`](crate::AnalyzerBuilder::target_fps)开启
+    TargetFpsChanged {
+        pid: Pid,
+        surface: SurfaceId,
+        target: TargetFps,
+    },
+}
+
+impl AnalyzerEvent {
+    /// 事件所属的应用，与应用无关的事件为None
+    #[must_use]
+    pub const fn pid(&self) -> Option<Pid> {
+        match self {
+            Self::Frame(event) => Some(event.pid),
+            Self::TargetExited { pid }
+            | Self::TargetRestarted { pid, .. }
+            | Self::ForegroundEntered { pid }
+            | Self::ForegroundLeft { pid }
+            | Self::TargetFpsChanged { pid, .. } => Some(*pid),
+            Self::Woken => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn frame(self) -> Option<FrameEvent> {
+        match self {
+            Self::Frame(event) => Some(event),
+            _ => None,
+        }
+    }
+}
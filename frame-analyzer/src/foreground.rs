@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::Pid;
+
+/// 用inotify监听前台cgroup的进程列表
+pub struct Foreground {
+    inotify: OwnedFd,
+    path: PathBuf,
+    /// 上一次读到的前台进程
+    pub pids: HashSet<Pid>,
+}
+
+impl Foreground {
+    pub fn watch(path: &Path) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mask = libc::IN_MODIFY | libc::IN_CLOSE_WRITE;
+        if unsafe { libc::inotify_add_watch(fd, c_path.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            inotify,
+            path: path.to_path_buf(),
+            pids: HashSet::new(),
+        })
+    }
+
+    /// 读空inotify事件，事件内容不重要，每次都重新读取整个文件
+    pub fn drain(&self) {
+        let mut buf = [0u8; 4096];
+        while unsafe { libc::read(self.inotify.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) }
+            > 0
+        {}
+    }
+
+    /// 当前的前台进程，文件暂时无法读取时为None
+    pub fn read(&self) -> Option<HashSet<Pid>> {
+        let content = fs::read_to_string(&self.path).ok()?;
+        Some(
+            content
+                .lines()
+                .filter_map(|line| line.trim().parse().ok())
+                .collect(),
+        )
+    }
+}
+
+impl AsRawFd for Foreground {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
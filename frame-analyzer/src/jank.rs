@@ -0,0 +1,402 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! PerfDog风格的Jank/BigJank判定和卡顿率
+//!
+//! 默认规则与PerfDog相同：帧时间超过前三帧平均值的两倍，
+//! 并且超过两个电影帧(1000/24 ms)时为Jank，超过三个电影帧时为BigJank
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{
+    FrameEvent, Pid, SurfaceId,
+    per_surface::PerSurface,
+    target_fps::{self, DEFAULT_CANDIDATES, DEFAULT_TOLERANCE},
+};
+
+// 电影的帧时间，1000/24 ms
+const MOVIE_FRAMETIME: Duration = Duration::from_nanos(41_666_667);
+// 相对判定使用的前几帧
+const PREVIOUS_FRAMES: usize = 3;
+// 推断vsync时参考的最近帧数
+const VSYNC_HISTORY: usize = 120;
+
+/// 一级卡顿的判定条件，设置了的条件必须全部满足，全部为None时不会判定为卡顿
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JankRule {
+    /// 帧时间至少是前三帧平均值的多少倍，不足三帧时不满足
+    pub relative: Option<f64>,
+    /// 帧时间的绝对下限
+    pub absolute: Option<Duration>,
+    /// 帧时间至少是多少个vsync周期，vsync未知时不满足
+    pub vsync_multiple: Option<f64>,
+}
+
+impl JankRule {
+    fn matches(
+        &self,
+        frametime: Duration,
+        previous: Option<Duration>,
+        vsync: Option<Duration>,
+    ) -> bool {
+        if self.relative.is_none() && self.absolute.is_none() && self.vsync_multiple.is_none() {
+            return false;
+        }
+
+        let relative = self.relative.is_none_or(|ratio| {
+            previous
+                .is_some_and(|previous| frametime.as_secs_f64() > previous.as_secs_f64() * ratio)
+        });
+        let absolute = self.absolute.is_none_or(|absolute| frametime > absolute);
+        let vsync = self.vsync_multiple.is_none_or(|multiple| {
+            vsync.is_some_and(|vsync| frametime.as_secs_f64() > vsync.as_secs_f64() * multiple)
+        });
+
+        relative && absolute && vsync
+    }
+}
+
+/// Jank和BigJank的判定规则，默认与PerfDog相同
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JankRules {
+    pub jank: JankRule,
+    pub big_jank: JankRule,
+}
+
+impl Default for JankRules {
+    fn default() -> Self {
+        Self {
+            jank: JankRule {
+                relative: Some(2.0),
+                absolute: Some(MOVIE_FRAMETIME * 2),
+                vsync_multiple: None,
+            },
+            big_jank: JankRule {
+                relative: Some(2.0),
+                absolute: Some(MOVIE_FRAMETIME * 3),
+                vsync_multiple: None,
+            },
+        }
+    }
+}
+
+/// 一帧的判定结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum JankLevel {
+    #[default]
+    Smooth,
+    Jank,
+    BigJank,
+}
+
+impl JankLevel {
+    /// `Jank`和`BigJank`都算卡顿
+    #[must_use]
+    pub const fn is_jank(self) -> bool {
+        !matches!(self, Self::Smooth)
+    }
+}
+
+/// 一段会话的卡顿统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JankSummary {
+    pub frames: u64,
+    /// 卡顿帧数，与PerfDog一样包含BigJank
+    pub janks: u64,
+    pub big_janks: u64,
+    /// 所有卡顿帧的帧时间之和
+    pub jank_time: Duration,
+    /// 所有帧的帧时间之和
+    pub total_time: Duration,
+}
+
+impl JankSummary {
+    /// 卡顿率，即卡顿帧时间占总时间的比例
+    #[must_use]
+    pub fn stutter(&self) -> f64 {
+        if self.total_time.is_zero() {
+            0.0
+        } else {
+            self.jank_time.as_secs_f64() / self.total_time.as_secs_f64()
+        }
+    }
+
+    fn record(&mut self, frametime: Duration, level: JankLevel) {
+        self.frames += 1;
+        self.total_time += frametime;
+        if level.is_jank() {
+            self.janks += 1;
+            self.jank_time += frametime;
+        }
+        if level == JankLevel::BigJank {
+            self.big_janks += 1;
+        }
+    }
+}
+
+/// 对一个surface的帧时间序列逐帧判定
+#[derive(Debug, Clone)]
+pub struct JankDetector {
+    rules: JankRules,
+    vsync: Option<Duration>,
+    history: VecDeque<Duration>,
+    summary: JankSummary,
+}
+
+impl Default for JankDetector {
+    fn default() -> Self {
+        Self::new(JankRules::default())
+    }
+}
+
+impl JankDetector {
+    #[must_use]
+    pub fn new(rules: JankRules) -> Self {
+        Self {
+            rules,
+            vsync: None,
+            history: VecDeque::with_capacity(VSYNC_HISTORY),
+            summary: JankSummary::default(),
+        }
+    }
+
+    /// 判定一帧并计入会话统计
+    pub fn push(&mut self, frametime: Duration) -> JankLevel {
+        let previous = self.previous_mean();
+        let vsync = self.vsync();
+        let level = if self.rules.big_jank.matches(frametime, previous, vsync) {
+            JankLevel::BigJank
+        } else if self.rules.jank.matches(frametime, previous, vsync) {
+            JankLevel::Jank
+        } else {
+            JankLevel::Smooth
+        };
+
+        if self.history.len() == VSYNC_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(frametime);
+        self.summary.record(frametime, level);
+
+        level
+    }
+
+    fn previous_mean(&self) -> Option<Duration> {
+        if self.history.len() < PREVIOUS_FRAMES {
+            return None;
+        }
+
+        let sum: Duration = self.history.iter().rev().take(PREVIOUS_FRAMES).sum();
+        Some(sum / PREVIOUS_FRAMES as u32)
+    }
+
+    /// 设置的vsync周期，没有设置时从最近帧时间推断刷新率
+    ///
+    /// 推断与[`target_fps`]相同，帧时间只有一簇时无法区分，认为vsync就是这个帧时间
+    #[must_use]
+    pub fn vsync(&self) -> Option<Duration> {
+        self.vsync.or_else(|| {
+            target_fps::infer(
+                self.history.iter().copied(),
+                &DEFAULT_CANDIDATES,
+                DEFAULT_TOLERANCE,
+            )
+            .map(|target| target.vsync())
+        })
+    }
+
+    /// 指定vsync周期，例如来自[`TargetFps::vsync`](crate::target_fps::TargetFps::vsync)；
+    /// None时恢复自动推断
+    pub const fn set_vsync(&mut self, vsync: Option<Duration>) {
+        self.vsync = vsync;
+    }
+
+    #[must_use]
+    pub const fn rules(&self) -> &JankRules {
+        &self.rules
+    }
+
+    #[must_use]
+    pub const fn summary(&self) -> JankSummary {
+        self.summary
+    }
+
+    /// 开始新的会话，保留vsync设置
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.summary = JankSummary::default();
+    }
+}
+
+/// 每个应用的每个surface各自一个[`JankDetector`]，直接消费分析器上报的帧
+#[derive(Debug, Clone, Default)]
+pub struct SurfaceJank {
+    rules: JankRules,
+    detectors: PerSurface<JankDetector>,
+}
+
+impl SurfaceJank {
+    #[must_use]
+    pub fn new(rules: JankRules) -> Self {
+        Self {
+            rules,
+            detectors: PerSurface::default(),
+        }
+    }
+
+    /// 是否判定空闲后恢复的帧，默认不判定
+    pub const fn set_include_idle(&mut self, include: bool) {
+        self.detectors.set_include_idle(include);
+    }
+
+    /// 判定一帧，可能合并了两帧的帧和默认情况下空闲后恢复的帧不参与判定，返回None
+    pub fn push(&mut self, event: &FrameEvent) -> Option<JankLevel> {
+        let detector = self.detectors.entry(event, || JankDetector::new(self.rules))?;
+        Some(detector.push(event.frametime))
+    }
+
+    #[must_use]
+    pub fn detector(&self, pid: Pid, surface: SurfaceId) -> Option<&JankDetector> {
+        self.detectors.get(pid, surface)
+    }
+
+    pub fn detector_mut(&mut self, pid: Pid, surface: SurfaceId) -> Option<&mut JankDetector> {
+        self.detectors.get_mut(pid, surface)
+    }
+
+    #[must_use]
+    pub fn summary(&self, pid: Pid, surface: SurfaceId) -> Option<JankSummary> {
+        self.detector(pid, surface).map(JankDetector::summary)
+    }
+
+    /// 所有判定过帧的(pid, surface)
+    pub fn surfaces(&self) -> impl Iterator<Item = (Pid, SurfaceId)> + '_ {
+        self.detectors.surfaces()
+    }
+
+    /// 删除该应用所有surface的会话
+    pub fn remove(&mut self, pid: Pid) {
+        self.detectors.remove(pid);
+    }
+
+    pub fn clear(&mut self) {
+        self.detectors.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn levels(detector: &mut JankDetector, frametimes: &[u64]) -> Vec<JankLevel> {
+        frametimes
+            .iter()
+            .map(|frametime| detector.push(ms(*frametime)))
+            .collect()
+    }
+
+    #[test]
+    fn perfdog_rules() {
+        use JankLevel::{BigJank, Jank, Smooth};
+
+        let mut detector = JankDetector::default();
+        // 不足三帧时不做相对判定
+        assert_eq!(levels(&mut detector, &[200, 16, 16]), [Smooth; 3]);
+        // 超过两个电影帧是Jank，超过三个是BigJank
+        assert_eq!(
+            levels(&mut detector, &[16, 90, 16, 16, 16, 130]),
+            [Smooth, Jank, Smooth, Smooth, Smooth, BigJank]
+        );
+        // 前三帧平均50ms，90ms不到两倍
+        assert_eq!(levels(&mut detector, &[50, 50, 50, 90]), [Smooth; 4]);
+
+        let summary = detector.summary();
+        assert_eq!(summary.frames, 13);
+        assert_eq!((summary.janks, summary.big_janks), (2, 1));
+        assert_eq!(summary.jank_time, ms(220));
+        assert!((summary.stutter() - 220.0 / 756.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn vsync_rules() {
+        let rule = JankRule {
+            relative: None,
+            absolute: None,
+            vsync_multiple: Some(2.0),
+        };
+        let mut detector = JankDetector::new(JankRules {
+            jank: rule,
+            big_jank: JankRule {
+                vsync_multiple: Some(4.0),
+                ..rule
+            },
+        });
+
+        // 8ms的帧推断为120Hz，vsync约8.3ms
+        assert_eq!(
+            levels(&mut detector, &[8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 20, 40]),
+            [
+                [JankLevel::Smooth; 10].as_slice(),
+                &[JankLevel::Jank, JankLevel::BigJank]
+            ]
+            .concat()
+        );
+
+        detector.set_vsync(Some(ms(16)));
+        assert_eq!(detector.push(ms(20)), JankLevel::Smooth);
+
+        // 还没有帧可以推断vsync时不判定为卡顿
+        let mut unknown = JankDetector::new(JankRules {
+            jank: rule,
+            big_jank: rule,
+        });
+        assert_eq!(unknown.push(ms(100)), JankLevel::Smooth);
+    }
+
+    #[test]
+    fn vsync_is_inferred_from_clusters() {
+        let rule = JankRule {
+            relative: None,
+            absolute: None,
+            vsync_multiple: Some(2.5),
+        };
+        let mut detector = JankDetector::new(JankRules {
+            jank: rule,
+            big_jank: JankRule {
+                vsync_multiple: Some(4.0),
+                ..rule
+            },
+        });
+
+        // 在60Hz的屏幕上锁30帧，偶尔晚一个vsync上屏
+        let vsync = Duration::from_secs(1) / 60;
+        for i in 0..60 {
+            let multiple = if i % 10 == 9 { 3 } else { 2 };
+            detector.push(vsync * multiple);
+        }
+        let inferred = detector.vsync().unwrap();
+        assert!(inferred.abs_diff(vsync) < Duration::from_micros(100), "{inferred:?}");
+
+        // 晚一个vsync的帧是2.5个vsync以上，按帧时间推断的vsync会漏判
+        assert_eq!(detector.push(vsync * 3), JankLevel::Jank);
+    }
+}
@@ -21,118 +21,468 @@
     clippy::module_name_repetitions,
     clippy::cast_possible_wrap,
     clippy::cast_sign_loss,
-    clippy::cast_possible_truncation
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss
 )]
 
 
 pub mod c_api;
 
 mod analyze_target;
+#[cfg(feature = "tokio")]
+mod async_analyzer;
+mod builder;
 mod ebpf;
 mod error;
+mod event;
+mod foreground;
+pub mod jank;
+mod per_surface;
+mod pidfd;
+mod preflight;
+mod process;
+mod report;
+pub mod selector;
+pub mod stats;
+mod symbols;
+pub mod target_fps;
 mod uprobe;
+mod waker;
 
 
 use std::{
     collections::{HashMap, VecDeque},
+    io,
     os::unix::io::AsRawFd,
-    time::Duration,
+    path::PathBuf,
+    time::{Duration, Instant},
 };
 
-use mio::{event::Event, Events, Interest, Poll, Token, unix::SourceFd};
+use mio::{Events, Interest, Poll, Token, unix::SourceFd};
 
 use analyze_target::AnalyzeTarget;
-pub use error::AnalyzerError;
+pub use analyze_target::{IdleThreshold, SurfaceInfo};
+#[cfg(feature = "tokio")]
+pub use async_analyzer::AsyncAnalyzer;
+pub use builder::AnalyzerBuilder;
+use ebpf::FrameBpf;
+pub use error::{AnalyzerError, ErrorKind};
 use error::Result;
-use uprobe::UprobeHandler;
+pub use event::{AnalyzerEvent, FrameEvent};
+pub use preflight::{Capabilities, PreflightReport, UprobeSupport, preflight};
+pub use report::{AttachAttempt, AttachFailure, AttachReport};
+pub use selector::SurfaceSelector;
+use foreground::Foreground;
+use frame_analyzer_ebpf_common::FrameSignal;
+use pidfd::PidFd;
+use process::{Followed, find_process};
+use stats::SurfaceStats;
+use target_fps::{SurfaceTargetFps, TargetFps};
+use uprobe::{ProbeConfig, UprobeHandler};
+pub use waker::AnalyzerWaker;
 
 
 pub type Pid = i32;
+/// surface的标识，即应用进程中`android::Surface`对象的地址
+pub type SurfaceId = u64;
 
-const EVENT_MAX: usize = 1024;
+const RING_TOKEN: Token = Token(usize::MAX);
+const WAKER_TOKEN: Token = Token(usize::MAX - 1);
+const FOREGROUND_TOKEN: Token = Token(usize::MAX - 2);
+// 其余token都是被监控应用的pid，对应它的pidfd
+// 跟随的进程退出后，每隔这么久扫描一次等待它重启
+const RESCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 附加新应用时如何处理已经在监控的应用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttachMode {
+    /// 同时监控多个应用，附加是累加的
+    #[default]
+    Multiple,
+    /// 只监控最近附加的应用，附加成功后解除其它所有应用
+    Single,
+}
+
+/// uprobe的附加范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProbeScope {
+    /// 每个应用附加一个只对该进程生效的uprobe
+    #[default]
+    PerProcess,
+    /// 只附加一个对所有进程生效的uprobe，增删应用时仅更新内核中的pid白名单
+    SystemWide,
+}
+
+/// 上报哪些surface的帧
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SurfaceFilter {
+    /// 只上报每个应用的主surface
+    #[default]
+    Main,
+    /// 上报所有surface，通过[`FrameEvent::is_main_surface`]区分
+    All,
+}
+
+/// 分析器运行时的统计
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnalyzerStats {
+    /// 因事件buffer已满丢弃的帧总数
+    pub lost_events: u64,
+    /// 每个cpu上丢弃的帧数，下标为cpu号
+    pub lost_events_per_cpu: Vec<u64>,
+}
 
 pub struct Analyzer {
-    poll: Option<Poll>,
+    poll: Poll,
+    mode: AttachMode,
+    scope: ProbeScope,
+    filter: SurfaceFilter,
+    selector: Box<dyn SurfaceSelector>,
+    history_len: usize,
+    idle_threshold: IdleThreshold,
+    event_batch: usize,
+    ring_size: u32,
+    probe: ProbeConfig,
+    bpf: Option<FrameBpf>,
+    waker: AnalyzerWaker,
+    pending: VecDeque<AnalyzerEvent>,
+    frame_stats: SurfaceStats,
+    target_fps: Option<SurfaceTargetFps>,
     map: HashMap<Pid, AnalyzeTarget>,
-    buffer: VecDeque<Pid>,
+    uprobes: HashMap<Pid, UprobeHandler>,
+    pidfds: HashMap<Pid, PidFd>,
+    followed: HashMap<String, Followed>,
+    last_scan: Instant,
+    foreground_procs: PathBuf,
+    foreground: Option<Foreground>,
+    system_uprobe: Option<UprobeHandler>,
 }
 
 impl Analyzer {
+    /// 以默认配置创建一个尚未监控任何应用的分析器
+    ///
+    /// eBPF程序在第一次附加应用时才加载，之后所有应用共享同一个程序和ring buffer
+    ///
+    /// # Errors
+    ///
+    /// 创建内部的poll实例失败
     pub fn new() -> Result<Self> {
-        let poll = None;
+        AnalyzerBuilder::new().build()
+    }
+
+    /// 以指定的uprobe附加范围创建分析器
+    ///
+    /// # Errors
+    ///
+    /// 创建内部的poll实例失败
+    pub fn with_scope(scope: ProbeScope) -> Result<Self> {
+        AnalyzerBuilder::new().probe_scope(scope).build()
+    }
+
+    #[must_use]
+    pub fn builder() -> AnalyzerBuilder {
+        AnalyzerBuilder::new()
+    }
+
+    fn from_builder(builder: AnalyzerBuilder) -> Result<Self> {
+        let poll = Poll::new()?;
+        let waker = AnalyzerWaker::new(poll.registry(), WAKER_TOKEN)?;
         let map = HashMap::new();
-        let buffer = VecDeque::with_capacity(EVENT_MAX);
+        let uprobes = HashMap::new();
+        let pidfds = HashMap::new();
+
+        Ok(Self {
+            poll,
+            mode: builder.mode,
+            scope: builder.scope,
+            filter: builder.filter,
+            selector: builder.selector,
+            history_len: builder.history_len,
+            idle_threshold: builder.idle_threshold,
+            event_batch: builder.event_batch,
+            ring_size: builder.ring_size,
+            probe: builder.probe,
+            bpf: None,
+            waker,
+            pending: VecDeque::with_capacity(builder.event_batch),
+            frame_stats: SurfaceStats::new(builder.stats_window),
+            target_fps: builder.target_fps.map(SurfaceTargetFps::new),
+            map,
+            uprobes,
+            pidfds,
+            followed: HashMap::new(),
+            last_scan: Instant::now(),
+            foreground_procs: builder.foreground_procs,
+            foreground: None,
+            system_uprobe: None,
+        })
+    }
+
+    #[must_use]
+    pub const fn scope(&self) -> ProbeScope {
+        self.scope
+    }
 
-        Ok(Self { poll, map, buffer })
+    pub const fn set_surface_filter(&mut self, filter: SurfaceFilter) {
+        self.filter = filter;
     }
 
+    #[must_use]
+    pub const fn surface_filter(&self) -> SurfaceFilter {
+        self.filter
+    }
+
+    /// 设置所有应用默认的主surface选择策略，默认为[`selector::LongestHistory`]
+    pub fn set_surface_selector<S: SurfaceSelector + 'static>(&mut self, selector: S) {
+        self.selector = Box::new(selector);
+    }
+
+    /// 为单个应用设置主surface选择策略，覆盖默认策略直到应用被解除
+    ///
+    /// # Errors
+    ///
+    /// 应用未被监控
+    pub fn set_app_surface_selector<S: SurfaceSelector + 'static>(
+        &mut self,
+        pid: Pid,
+        selector: S,
+    ) -> Result<()> {
+        self.map
+            .get_mut(&pid)
+            .ok_or(AnalyzerError::NotAttached { pid })?
+            .set_selector(Some(Box::new(selector)));
+        Ok(())
+    }
+
+    /// 让应用重新使用默认的主surface选择策略
+    pub fn clear_app_surface_selector(&mut self, pid: Pid) {
+        if let Some(target) = self.map.get_mut(&pid) {
+            target.set_selector(None);
+        }
+    }
+
+    /// 设置附加模式，只影响之后的[`Analyzer::attach_app`]调用
+    pub const fn set_attach_mode(&mut self, mode: AttachMode) {
+        self.mode = mode;
+    }
+
+    #[must_use]
+    pub const fn attach_mode(&self) -> AttachMode {
+        self.mode
+    }
+
+    /// 开始监控一个应用
+    ///
+    /// 默认模式下已经在监控的应用不受影响，[`AttachMode::Single`]模式下附加成功后会解除其它应用
+    ///
+    /// 应用退出后会被自动解除，并产生[`AnalyzerEvent::TargetExited`]
+    ///
+    /// # Errors
+    ///
+    /// 进程不存在，加载eBPF程序失败，或者附加uprobe失败
     pub fn attach_app(&mut self, pid: Pid) -> Result<()> {
         // 如果已经监控这个PID，直接返回
         if self.map.contains_key(&pid) {
             return Ok(());
         }
 
-        // 删除所有旧的监控（只保留最新的一个）
-        self.detach_apps();
+        self.attach_target(pid, None)
+    }
 
-        let uprobe = UprobeHandler::attach_app(pid)?;
-        self.map.insert(pid, AnalyzeTarget::new(uprobe));
-        self.register_poll()?;
+    /// 按进程名开始监控一个应用，返回当前的pid
+    ///
+    /// 进程名是`/proc/<pid>/cmdline`的第一个参数，安卓应用即包名，也可以是可执行文件名。
+    /// 进程退出后继续跟随这个名称，以新pid重启时自动重新附加，并产生[`AnalyzerEvent::TargetRestarted`]
+    ///
+    /// # Errors
+    ///
+    /// 找不到该名称的进程，或者附加失败
+    pub fn attach_process_name(&mut self, name: &str) -> Result<Pid> {
+        let pid = find_process(&self.probe.proc_root, name).ok_or_else(|| {
+            AnalyzerError::ProcessNotFound {
+                name: name.to_string(),
+            }
+        })?;
+        self.attach_app(pid)?;
+        self.followed.insert(name.to_string(), Followed::new(pid));
+        Ok(pid)
+    }
+
+    /// 自动监控前台应用
+    ///
+    /// 用inotify监听[`AnalyzerBuilder::foreground_procs`]，文件每次被修改都重新读取：
+    /// 新出现的进程被附加并产生[`AnalyzerEvent::ForegroundEntered`]，
+    /// 消失的进程被解除并产生[`AnalyzerEvent::ForegroundLeft`]，
+    /// 前台进程退出时在[`AnalyzerEvent::TargetExited`]之后同样产生该事件。
+    /// 前台可能同时有多个进程，应当配合[`AttachMode::Multiple`]使用
+    ///
+    /// # Errors
+    ///
+    /// 无法监听进程列表文件
+    pub fn follow_foreground(&mut self) -> Result<()> {
+        if self.foreground.is_some() {
+            return Ok(());
+        }
+
+        let foreground = Foreground::watch(&self.foreground_procs)
+            .map_err(|err| AnalyzerError::io_with_path(&self.foreground_procs, err))?;
+        self.poll.registry().register(
+            &mut SourceFd(&foreground.as_raw_fd()),
+            FOREGROUND_TOKEN,
+            Interest::READABLE,
+        )?;
+        self.foreground = Some(foreground);
+        self.update_foreground();
 
         Ok(())
     }
 
+    /// 停止跟随前台，已经附加的应用保持不变
+    pub fn stop_following_foreground(&mut self) {
+        if let Some(foreground) = self.foreground.take() {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&foreground.as_raw_fd()));
+        }
+    }
+
+    #[must_use]
+    pub const fn is_following_foreground(&self) -> bool {
+        self.foreground.is_some()
+    }
+
+    // 重启的进程可以复用旧进程留下的探针
+    fn attach_target(&mut self, pid: Pid, uprobe: Option<UprobeHandler>) -> Result<()> {
+        let pidfd = PidFd::open(pid).map_err(|err| AnalyzerError::io_with_pid(pid, err))?;
+
+        let uprobe = match (self.scope, uprobe) {
+            (ProbeScope::PerProcess, Some(mut uprobe)) => {
+                let program = load_bpf(&mut self.bpf, &self.poll, self.ring_size)?.program()?;
+                uprobe.reattach(program, &self.probe, pid)?;
+                Some(uprobe)
+            }
+            (ProbeScope::PerProcess, None) => Some(self.attach_probe(Some(pid))?),
+            (ProbeScope::SystemWide, _) => {
+                if self.system_uprobe.is_none() {
+                    self.system_uprobe = Some(self.attach_probe(None)?);
+                }
+                None
+            }
+        };
+
+        if self.mode == AttachMode::Single {
+            self.detach_apps();
+        }
+
+        self.bpf()?.allow(pid)?;
+        if let Some(uprobe) = uprobe {
+            self.uprobes.insert(pid, uprobe);
+        }
+        if let Some(pidfd) = pidfd {
+            self.watch(pid, pidfd)?;
+        }
+        self.map.insert(pid, AnalyzeTarget::new(self.history_len, self.idle_threshold));
+
+        Ok(())
+    }
+
+    /// 停止监控一个应用
+    ///
+    /// # Errors
+    ///
+    /// 从内核pid白名单中移除失败
     pub fn detach_app(&mut self, pid: Pid) -> Result<()> {
+        // 已经退出的应用保留统计和跟随，直到被显式解除
+        self.frame_stats.remove(pid);
+        if let Some(target_fps) = &mut self.target_fps {
+            target_fps.remove(pid);
+        }
+        self.followed.retain(|_, followed| followed.pid != pid);
         if !self.map.contains_key(&pid) {
             return Ok(());
         }
 
-        self.map.remove(&pid).ok_or(AnalyzerError::AppNotFound)?;
-        self.buffer.retain(|pid_event| *pid_event != pid);
-        self.register_poll()?;
-
-        Ok(())
+        self.pending.retain(|event| event.pid() != Some(pid));
+        self.remove_target(pid)
     }
 
     pub fn detach_apps(&mut self) {
+        if let Some(bpf) = &mut self.bpf {
+            for pid in self.map.keys() {
+                let _ = bpf.disallow(*pid);
+            }
+        }
+
+        for pidfd in self.pidfds.values() {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&pidfd.as_raw_fd()));
+        }
+
         self.map.clear();
-        self.buffer.clear();
+        self.uprobes.clear();
+        self.pidfds.clear();
+        self.followed.clear();
+        self.pending.clear();
+        self.frame_stats.clear();
+        if let Some(target_fps) = &mut self.target_fps {
+            target_fps.clear();
+        }
+        if let Some(foreground) = &mut self.foreground {
+            foreground.pids.clear();
+        }
     }
 
+    /// 被[`AnalyzerWaker`]唤醒或者收到其它事件时返回None，需要区分时使用[`Analyzer::recv_event`]
     pub fn recv(&mut self) -> Option<(Pid, Duration)> {
-        if self.buffer.is_empty() {
-            if let Some(ref mut poll) = self.poll {
-                let mut events = Events::with_capacity(EVENT_MAX);
-                let _ = poll.poll(&mut events, None);
+        self.recv_frame().map(|event| (event.pid, event.frametime))
+    }
 
-                self.buffer.extend(events.iter().map(event_to_pid));
-            }
+    pub fn recv_timeout(&mut self, time: Duration) -> Option<(Pid, Duration)> {
+        self.recv_frame_timeout(time)
+            .map(|event| (event.pid, event.frametime))
+    }
 
-            let _ = self.register_poll();
-        }
+    /// 不阻塞，没有已经到达的帧时立即返回None
+    pub fn try_recv(&mut self) -> Option<(Pid, Duration)> {
+        self.try_recv_frame()
+            .map(|event| (event.pid, event.frametime))
+    }
 
-        let pid = self.buffer.pop_front()?;
-        let frametime = self.map.get_mut(&pid)?.update()?;
+    /// 与[`Analyzer::recv`]相同，但返回包含surface、时间戳和线程信息的[`FrameEvent`]
+    pub fn recv_frame(&mut self) -> Option<FrameEvent> {
+        self.recv_event().and_then(AnalyzerEvent::frame)
+    }
 
-        Some((pid, frametime))
+    pub fn recv_frame_timeout(&mut self, time: Duration) -> Option<FrameEvent> {
+        self.recv_event_timeout(time).and_then(AnalyzerEvent::frame)
     }
 
-    pub fn recv_timeout(&mut self, time: Duration) -> Option<(Pid, Duration)> {
-        if self.buffer.is_empty() {
-            if let Some(ref mut poll) = self.poll {
-                let mut events = Events::with_capacity(EVENT_MAX);
-                let _ = poll.poll(&mut events, Some(time));
+    pub fn try_recv_frame(&mut self) -> Option<FrameEvent> {
+        self.try_recv_event().and_then(AnalyzerEvent::frame)
+    }
 
-                self.buffer.extend(events.iter().map(event_to_pid));
-            }
+    /// 阻塞直到有事件，poll出错时返回None
+    pub fn recv_event(&mut self) -> Option<AnalyzerEvent> {
+        self.recv_inner(None)
+    }
 
-            let _ = self.register_poll();
-        }
+    /// 超时返回None
+    pub fn recv_event_timeout(&mut self, time: Duration) -> Option<AnalyzerEvent> {
+        self.recv_inner(Some(time))
+    }
 
-        let pid = self.buffer.pop_front()?;
-        let frametime = self.map.get_mut(&pid)?.update()?;
+    /// 不阻塞，只返回已经到达的事件
+    pub fn try_recv_event(&mut self) -> Option<AnalyzerEvent> {
+        self.try_recv_inner()
+    }
 
-        Some((pid, frametime))
+    /// 获取唤醒句柄，可以在其它线程中打断阻塞的接收
+    #[must_use]
+    pub fn waker(&self) -> AnalyzerWaker {
+        self.waker.clone()
     }
 
     #[must_use]
@@ -144,27 +494,888 @@ impl Analyzer {
         self.map.keys().copied()
     }
 
-    fn register_poll(&mut self) -> Result<()> {
-        let poll = if let Some(existing) = self.poll.take() {
-            existing
-        } else {
-            Poll::new()?
+    /// 读取内核中的丢帧计数，eBPF程序尚未加载时全部为0
+    ///
+    /// # Errors
+    ///
+    /// eBPF map读取失败
+    pub fn stats(&self) -> Result<AnalyzerStats> {
+        let Some(bpf) = &self.bpf else {
+            return Ok(AnalyzerStats::default());
         };
 
-        for (pid, handler) in &mut self.map {
-            poll.registry().register(
-                &mut SourceFd(&handler.uprobe.ring()?.as_raw_fd()),
-                Token(*pid as usize),
-                Interest::READABLE,
-            )?;
+        let lost_events_per_cpu = bpf.lost_events()?;
+        Ok(AnalyzerStats {
+            lost_events: lost_events_per_cpu.iter().sum(),
+            lost_events_per_cpu,
+        })
+    }
+
+    /// 每个应用每个surface最近一段时间的帧统计，窗口由[`AnalyzerBuilder::stats_window`]设置
+    ///
+    /// 只包含上报过的帧，不包含可能合并了两帧的帧和空闲后恢复的帧；应用退出后保留到被解除
+    #[must_use]
+    pub const fn frame_stats(&self) -> &SurfaceStats {
+        &self.frame_stats
+    }
+
+    /// 用于调整统计选项，例如[`SurfaceStats::set_include_idle`]
+    pub const fn frame_stats_mut(&mut self) -> &mut SurfaceStats {
+        &mut self.frame_stats
+    }
+
+    /// 该surface最近一次确认的目标帧率，没有开启推断或者还没有结果时为None
+    #[must_use]
+    pub fn target_fps(&self, pid: Pid, surface: SurfaceId) -> Option<TargetFps> {
+        self.target_fps.as_ref()?.current(pid, surface)
+    }
+
+    /// 应用的探针实际附加的queueBuffer符号，应用未被监控时为None
+    ///
+    /// [`ProbeScope::SystemWide`]下所有应用共享同一组符号
+    #[must_use]
+    pub fn probe_symbols(&self, pid: Pid) -> Option<&[String]> {
+        if !self.map.contains_key(&pid) {
+            return None;
+        }
+
+        self.uprobes
+            .get(&pid)
+            .or(self.system_uprobe.as_ref())
+            .map(UprobeHandler::symbols)
+    }
+
+    /// 应用最近一次附加uprobe时的完整尝试记录，应用未被监控时为None
+    ///
+    /// 附加失败时同样的报告放在[`AnalyzerError::AttachFailed`]中
+    #[must_use]
+    pub fn attach_report(&self, pid: Pid) -> Option<&AttachReport> {
+        if !self.map.contains_key(&pid) {
+            return None;
+        }
+
+        self.uprobes
+            .get(&pid)
+            .or(self.system_uprobe.as_ref())
+            .map(UprobeHandler::report)
+    }
+
+    /// 列出应用目前出现过的所有surface，应用未被监控时为空
+    pub fn surfaces(&self, pid: Pid) -> impl Iterator<Item = SurfaceInfo> + '_ {
+        self.map
+            .get(&pid)
+            .map(|target| target.surfaces(self.selector.as_ref()))
+            .unwrap_or_default()
+            .into_iter()
+    }
+
+    fn bpf(&mut self) -> Result<&mut FrameBpf> {
+        load_bpf(&mut self.bpf, &self.poll, self.ring_size)
+    }
+
+    // 用pid作为token把pidfd注册到poll，进程退出时会收到通知
+    fn watch(&mut self, pid: Pid, pidfd: PidFd) -> Result<()> {
+        self.poll.registry().register(
+            &mut SourceFd(&pidfd.as_raw_fd()),
+            Token(pid as usize),
+            Interest::READABLE,
+        )?;
+        self.pidfds.insert(pid, pidfd);
+        Ok(())
+    }
+
+    // 不清理队列中已经产生的事件，ring中残留的该应用事件在路由时会因找不到目标被丢弃
+    fn remove_target(&mut self, pid: Pid) -> Result<()> {
+        self.map
+            .remove(&pid)
+            .ok_or(AnalyzerError::NotAttached { pid })?;
+        self.uprobes.remove(&pid);
+        if let Some(pidfd) = self.pidfds.remove(&pid) {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&pidfd.as_raw_fd()));
+        }
+
+        if let Some(bpf) = &mut self.bpf {
+            bpf.disallow(pid)?;
         }
 
-        self.poll = Some(poll);
         Ok(())
     }
+
+    // 先读空ring，保证退出事件排在该应用最后一帧之后
+    fn handle_exited(&mut self, pid: Pid) {
+        if !self.map.contains_key(&pid) {
+            return;
+        }
+
+        self.drain_ring();
+        let uprobe = self.uprobes.remove(&pid);
+        if let Some(followed) = self
+            .followed
+            .values_mut()
+            .find(|followed| followed.pid == pid)
+        {
+            followed.exited = true;
+            followed.uprobe = uprobe;
+        }
+
+        let _ = self.remove_target(pid);
+        self.pending.push_back(AnalyzerEvent::TargetExited { pid });
+
+        // 前台进程退出时进程列表文件不一定会被写入，主动重新读取一次
+        if let Some(foreground) = &mut self.foreground
+            && foreground.pids.remove(&pid)
+        {
+            self.pending.push_back(AnalyzerEvent::ForegroundLeft { pid });
+            self.update_foreground();
+        }
+    }
+
+    // 先解除离开的进程再附加新进程，附加失败的进程在下次文件变化时重试
+    fn update_foreground(&mut self) {
+        let Some(foreground) = &self.foreground else {
+            return;
+        };
+        foreground.drain();
+        let Some(current) = foreground.read() else {
+            return;
+        };
+
+        let mut left: Vec<_> = foreground.pids.difference(&current).copied().collect();
+        let mut entered: Vec<_> = current.difference(&foreground.pids).copied().collect();
+        left.sort_unstable();
+        entered.sort_unstable();
+
+        for pid in left {
+            let _ = self.detach_app(pid);
+            self.pending.push_back(AnalyzerEvent::ForegroundLeft { pid });
+            if let Some(foreground) = &mut self.foreground {
+                foreground.pids.remove(&pid);
+            }
+        }
+
+        for pid in entered {
+            if self.attach_app(pid).is_err() {
+                continue;
+            }
+
+            self.pending.push_back(AnalyzerEvent::ForegroundEntered { pid });
+            if let Some(foreground) = &mut self.foreground {
+                foreground.pids.insert(pid);
+            }
+        }
+    }
+
+    fn waiting_restart(&self) -> bool {
+        self.followed.values().any(|followed| followed.exited)
+    }
+
+    // 扫描已经退出的跟随进程是否以新pid重启，限制扫描频率
+    fn rescan_followed(&mut self) {
+        if !self.waiting_restart() || self.last_scan.elapsed() < RESCAN_INTERVAL {
+            return;
+        }
+        self.last_scan = Instant::now();
+
+        let names: Vec<_> = self
+            .followed
+            .iter()
+            .filter(|(_, followed)| followed.exited)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in names {
+            let Some(pid) = find_process(&self.probe.proc_root, &name) else {
+                continue;
+            };
+
+            // Single模式下附加会清空followed，所以先取出来
+            let Some(mut followed) = self.followed.remove(&name) else {
+                continue;
+            };
+
+            let attached =
+                self.map.contains_key(&pid) || self.attach_target(pid, followed.uprobe.take()).is_ok();
+            if attached {
+                self.frame_stats.remove(followed.pid);
+                if let Some(target_fps) = &mut self.target_fps {
+                    target_fps.remove(followed.pid);
+                }
+                self.pending.push_back(AnalyzerEvent::TargetRestarted {
+                    old_pid: followed.pid,
+                    pid,
+                });
+                followed = Followed::new(pid);
+            }
+
+            self.followed.insert(name, followed);
+        }
+    }
+
+    // 等待跟随的进程重启时，阻塞时间不超过扫描间隔
+    fn wait_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        if !self.waiting_restart() {
+            return timeout;
+        }
+
+        let until_scan = RESCAN_INTERVAL.saturating_sub(self.last_scan.elapsed());
+        Some(timeout.map_or(until_scan, |timeout| timeout.min(until_scan)))
+    }
+
+    // pid为None时附加到所有进程
+    fn attach_probe(&mut self, pid: Option<Pid>) -> Result<UprobeHandler> {
+        let program = load_bpf(&mut self.bpf, &self.poll, self.ring_size)?.program()?;
+
+        match pid {
+            Some(pid) => UprobeHandler::attach_app(program, &self.probe, pid),
+            None => UprobeHandler::attach_system(program, &self.probe),
+        }
+    }
+
+    // 阻塞直到有事件或者超时，poll出错时也返回None
+    fn recv_inner(&mut self, timeout: Option<Duration>) -> Option<AnalyzerEvent> {
+        // 超时太长无法表示为时间点时等同于一直等待
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+
+        loop {
+            if let Some(event) = self.try_recv_inner() {
+                return Some(event);
+            }
+
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let wait_for = self.wait_timeout(timeout);
+            if !self.wait(wait_for) && wait_for == timeout {
+                return None;
+            }
+        }
+    }
+
+    // 不阻塞，只读取已经在队列或者ring中的事件
+    fn try_recv_inner(&mut self) -> Option<AnalyzerEvent> {
+        self.rescan_followed();
+
+        // ring只在第一次加载时注册一次，poll是边缘触发的
+        // 所以每次唤醒都要把ring读空，否则剩余的数据不会再有通知
+        if self.pending.is_empty() {
+            self.drain_ring();
+        }
+
+        self.pending.pop_front()
+    }
+
+    // 等待poll上的事件，超时或出错时返回false
+    // 唤醒事件放入队列，ring中的数据在下一次try_recv_inner时读取
+    fn wait(&mut self, timeout: Option<Duration>) -> bool {
+        let mut events = Events::with_capacity(self.event_batch);
+        match self.poll.poll(&mut events, timeout) {
+            // mio不会自动重试被信号打断的等待，让调用者按剩余时间重新等待
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => return true,
+            Err(_) => return false,
+            Ok(()) if events.is_empty() => return false,
+            Ok(()) => (),
+        }
+
+        let mut woken = false;
+        let mut foreground_changed = false;
+        let mut exited = Vec::new();
+        for event in &events {
+            match event.token() {
+                RING_TOKEN => (),
+                WAKER_TOKEN => woken = true,
+                FOREGROUND_TOKEN => foreground_changed = true,
+                Token(pid) => exited.push(pid as Pid),
+            }
+        }
+
+        for pid in exited {
+            self.handle_exited(pid);
+        }
+
+        if foreground_changed {
+            self.update_foreground();
+        }
+
+        if woken {
+            self.pending.push_back(AnalyzerEvent::Woken);
+        }
+
+        true
+    }
+
+    fn drain_ring(&mut self) {
+        let Some(bpf) = &mut self.bpf else {
+            return;
+        };
+
+        for signal in bpf.drain() {
+            if let Some(event) = self.handle_signal(&signal) {
+                self.frame_stats.push(&event);
+                self.pending.push_back(AnalyzerEvent::Frame(event));
+
+                if let Some(target) = self
+                    .target_fps
+                    .as_mut()
+                    .and_then(|target_fps| target_fps.push(&event))
+                {
+                    self.pending.push_back(AnalyzerEvent::TargetFpsChanged {
+                        pid: event.pid,
+                        surface: event.surface,
+                        target,
+                    });
+                }
+            }
+        }
+    }
+
+    fn handle_signal(&mut self, signal: &FrameSignal) -> Option<FrameEvent> {
+        let pid = signal.tgid as Pid;
+        let frame = self
+            .map
+            .get_mut(&pid)?
+            .update(signal, self.selector.as_ref())?;
+        match self.filter {
+            SurfaceFilter::Main if !frame.is_main => None,
+            _ => Some(FrameEvent::new(signal, frame)),
+        }
+    }
+}
+
+// 懒加载共享的eBPF对象，并把事件buffer的fd注册到poll
+// 只借用需要的字段，调用者可以同时借用probe配置
+fn load_bpf<'a>(
+    bpf: &'a mut Option<FrameBpf>,
+    poll: &Poll,
+    ring_size: u32,
+) -> Result<&'a mut FrameBpf> {
+    if let Some(loaded) = bpf.take() {
+        return Ok(bpf.insert(loaded));
+    }
+
+    let loaded = FrameBpf::load(ring_size)?;
+    loaded.register(poll.registry(), RING_TOKEN)?;
+
+    Ok(bpf.insert(loaded))
 }
 
-fn event_to_pid(event: &Event) -> Pid {
-    let Token(pid) = event.token();
-    pid as Pid
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::{FakeProc, fake_proc};
+
+    fn signal(pid: Pid, ktime_ms: u64, buffer: SurfaceId) -> FrameSignal {
+        FrameSignal::new(ktime_ms * 1_000_000, buffer, pid as u32, pid as u32)
+    }
+
+    fn frame(analyzer: &mut Analyzer, signal: &FrameSignal) -> Option<(Pid, Duration)> {
+        analyzer
+            .handle_signal(signal)
+            .map(|event| (event.pid, event.frametime))
+    }
+
+    fn analyzer_with(pids: &[Pid]) -> Analyzer {
+        let mut analyzer = Analyzer::new().unwrap();
+        for &pid in pids {
+            analyzer.map.insert(
+                pid,
+                AnalyzeTarget::new(analyzer.history_len, analyzer.idle_threshold),
+            );
+        }
+        analyzer
+    }
+
+    // 按名称跟随pid 3，假procfs中同名的进程已经以pid 7重启
+    fn followed_game(name: &str) -> (Analyzer, FakeProc) {
+        let root = fake_proc(name, &[("7", b"com.example.game\0")]);
+        let mut analyzer = analyzer_with(&[3]);
+        analyzer.probe.proc_root = root.to_path_buf();
+        analyzer
+            .followed
+            .insert("com.example.game".to_string(), Followed::new(3));
+        (analyzer, root)
+    }
+
+    #[test]
+    fn attach_mode_defaults_to_multiple() {
+        let analyzer = Analyzer::new().unwrap();
+        assert_eq!(analyzer.attach_mode(), AttachMode::Multiple);
+    }
+
+    #[test]
+    fn interleaved_pids_keep_separate_frametimes() {
+        let mut analyzer = analyzer_with(&[100, 200, 300]);
+
+        // 三个应用交替提交帧，各自的帧间隔分别为8ms、16ms、33ms
+        let mut received = Vec::new();
+        for n in 0..4 {
+            for (pid, interval) in [(100, 8), (200, 16), (300, 33)] {
+                received.extend(frame(&mut analyzer, &signal(pid, n * interval, 0x1000)));
+            }
+        }
+
+        for (pid, interval) in [(100, 8), (200, 16), (300, 33)] {
+            let frametimes: Vec<_> = received
+                .iter()
+                .filter(|(p, _)| *p == pid)
+                .map(|(_, frametime)| *frametime)
+                .collect();
+            assert_eq!(frametimes, vec![Duration::from_millis(interval); 3]);
+        }
+    }
+
+    #[test]
+    fn same_buffer_address_in_different_pids_is_independent() {
+        let mut analyzer = analyzer_with(&[1, 2]);
+
+        assert_eq!(frame(&mut analyzer, &signal(1, 0, 0xdead)), None);
+        assert_eq!(frame(&mut analyzer, &signal(2, 5, 0xdead)), None);
+        assert_eq!(
+            frame(&mut analyzer, &signal(1, 10, 0xdead)),
+            Some((1, Duration::from_millis(10)))
+        );
+        assert_eq!(
+            frame(&mut analyzer, &signal(2, 25, 0xdead)),
+            Some((2, Duration::from_millis(20)))
+        );
+    }
+
+    #[test]
+    fn signals_are_routed_by_tgid() {
+        let mut analyzer = analyzer_with(&[1000]);
+
+        // 同一进程不同线程提交的帧属于同一个目标
+        frame(&mut analyzer, &FrameSignal::new(0, 0x1, 1000, 1000));
+        assert_eq!(
+            frame(
+                &mut analyzer,
+                &FrameSignal::new(16_000_000, 0x1, 1000, 1024)
+            ),
+            Some((1000, Duration::from_millis(16)))
+        );
+
+        // 未附加进程的事件被丢弃
+        assert_eq!(frame(&mut analyzer, &signal(2000, 0, 0x1)), None);
+        assert_eq!(frame(&mut analyzer, &signal(2000, 16, 0x1)), None);
+    }
+
+    #[test]
+    fn frame_event_carries_thread_info() {
+        let mut analyzer = analyzer_with(&[1000]);
+        let mut comm = [0; 16];
+        comm[..12].copy_from_slice(b"RenderThread");
+
+        analyzer.handle_signal(&FrameSignal::new(0, 0x1, 1000, 1010).with_task(2, comm));
+        let event = analyzer
+            .handle_signal(&FrameSignal::new(8_000_000, 0x1, 1000, 1010).with_task(5, comm))
+            .unwrap();
+
+        assert_eq!(event.pid, 1000);
+        assert_eq!(event.tid, 1010);
+        assert_eq!(event.cpu, 5);
+        assert_eq!(event.thread_name(), "RenderThread");
+        assert_eq!(event.frametime, Duration::from_millis(8));
+    }
+
+    #[test]
+    fn frame_event_keeps_surface_and_absolute_time() {
+        let mut analyzer = analyzer_with(&[1]);
+
+        analyzer.handle_signal(&signal(1, 1000, 0xa));
+        analyzer.handle_signal(&signal(1, 1004, 0xb));
+        let event = analyzer.handle_signal(&signal(1, 1016, 0xa)).unwrap();
+        assert_eq!(event.surface, 0xa);
+        assert_eq!(event.ktime_ns, 1_016_000_000);
+        assert_eq!(event.frametime, Duration::from_millis(16));
+        assert!(event.is_main_surface);
+
+        // 0xb历史长度与0xa相同但帧时间总和更大，不是主surface，不上报
+        assert_eq!(analyzer.handle_signal(&signal(1, 1036, 0xb)), None);
+    }
+
+    #[test]
+    fn all_surfaces_filter_reports_secondary_frames() {
+        let mut analyzer = analyzer_with(&[1]);
+        analyzer.set_surface_filter(SurfaceFilter::All);
+
+        // UI surface 60fps，视频 SurfaceView 30fps
+        let mut events = Vec::new();
+        for n in 0..7 {
+            events.extend(analyzer.handle_signal(&signal(1, n * 16, 0xa)));
+            if n % 2 == 0 {
+                events.extend(analyzer.handle_signal(&signal(1, n * 16 + 1, 0xb)));
+            }
+        }
+
+        let video: Vec<_> = events.iter().filter(|e| e.surface == 0xb).collect();
+        assert_eq!(video.len(), 3);
+        assert!(video.iter().all(|e| !e.is_main_surface));
+        assert!(
+            video
+                .iter()
+                .all(|e| e.frametime == Duration::from_millis(32))
+        );
+        assert_eq!(events.iter().filter(|e| e.surface == 0xa).count(), 6);
+
+        let mut surfaces: Vec<_> = analyzer.surfaces(1).collect();
+        surfaces.sort_by_key(|surface| surface.id);
+        assert_eq!(surfaces.len(), 2);
+        assert_eq!((surfaces[0].frames, surfaces[1].frames), (7, 4));
+        assert!(surfaces[0].is_main && !surfaces[1].is_main);
+        assert!((surfaces[0].recent_fps - 62.5).abs() < 1e-6);
+        assert!((surfaces[1].recent_fps - 31.25).abs() < 1e-6);
+        assert_eq!(analyzer.surfaces(2).count(), 0);
+    }
+
+    #[test]
+    fn surface_selector_can_be_overridden_per_pid() {
+        use selector::{HighestRecentRate, PinnedSurface};
+
+        let mut analyzer = analyzer_with(&[1, 2]);
+
+        // 低帧率的HUD先出现，历史更长；3D画面随后以更高帧率提交
+        for pid in [1, 2] {
+            for n in 0..5 {
+                analyzer.handle_signal(&signal(pid, n * 100, 0x40d));
+            }
+            for n in 0..3 {
+                analyzer.handle_signal(&signal(pid, 400 + n * 16, 0x3d));
+            }
+        }
+
+        let main = |analyzer: &Analyzer, pid| {
+            analyzer
+                .surfaces(pid)
+                .find(|surface| surface.is_main)
+                .map(|surface| surface.id)
+        };
+        assert_eq!(main(&analyzer, 1), Some(0x40d));
+
+        analyzer.set_surface_selector(HighestRecentRate);
+        analyzer
+            .set_app_surface_selector(2, PinnedSurface(0x40d))
+            .unwrap();
+        assert_eq!(main(&analyzer, 1), Some(0x3d));
+        assert_eq!(main(&analyzer, 2), Some(0x40d));
+
+        analyzer.clear_app_surface_selector(2);
+        assert_eq!(main(&analyzer, 2), Some(0x3d));
+        assert!(
+            analyzer
+                .set_app_surface_selector(3, PinnedSurface(0))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn merged_flag_marks_only_the_affected_frame() {
+        use frame_analyzer_ebpf_common::FRAME_FLAG_MERGED;
+
+        let mut analyzer = analyzer_with(&[1]);
+
+        analyzer.handle_signal(&signal(1, 0, 0x1));
+        let normal = analyzer.handle_signal(&signal(1, 16, 0x1)).unwrap();
+        // 32ms处的一帧在内核中被丢弃，下一帧的帧时间覆盖了两帧
+        let merged = analyzer
+            .handle_signal(&signal(1, 48, 0x1).with_flags(FRAME_FLAG_MERGED))
+            .unwrap();
+        let next = analyzer.handle_signal(&signal(1, 64, 0x1)).unwrap();
+
+        assert!(!normal.possibly_merged);
+        assert!(merged.possibly_merged);
+        assert_eq!(merged.frametime, Duration::from_millis(32));
+        assert!(!next.possibly_merged);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_recv_rescans_followed_process() {
+        let (mut analyzer, _root) = followed_game("async-restart");
+        analyzer.handle_exited(3);
+        analyzer
+            .map
+            .insert(7, AnalyzeTarget::new(144, IdleThreshold::default()));
+
+        // 没有任何fd就绪，只有定时器能触发重新扫描
+        let mut analyzer = AsyncAnalyzer::new(analyzer).unwrap();
+        assert_eq!(
+            analyzer.recv_event().await,
+            Some(AnalyzerEvent::TargetExited { pid: 3 })
+        );
+        let start = Instant::now();
+        let restarted = tokio::time::timeout(RESCAN_INTERVAL * 4, analyzer.recv_event()).await;
+        assert_eq!(
+            restarted.ok().flatten(),
+            Some(AnalyzerEvent::TargetRestarted { old_pid: 3, pid: 7 })
+        );
+        assert!(start.elapsed() < RESCAN_INTERVAL * 2);
+    }
+
+    #[test]
+    fn stats_are_zero_before_loading() {
+        let analyzer = Analyzer::new().unwrap();
+        assert_eq!(analyzer.stats().unwrap(), AnalyzerStats::default());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_recv_is_cancel_safe() {
+        let mut analyzer = AsyncAnalyzer::new(analyzer_with(&[1])).unwrap();
+
+        // 没有帧时超时取消，不影响之后的接收
+        let timeout = Duration::from_millis(10);
+        assert!(
+            tokio::time::timeout(timeout, analyzer.recv_frame())
+                .await
+                .is_err()
+        );
+
+        // 流存在期间仍然可以修改Analyzer
+        analyzer
+            .map
+            .insert(2, AnalyzeTarget::new(144, IdleThreshold::default()));
+        for (pid, ktime) in [(1, 0), (2, 0), (1, 16), (2, 8)] {
+            let event = analyzer.handle_signal(&signal(pid, ktime, 0x1));
+            analyzer.pending.extend(event.map(AnalyzerEvent::Frame));
+        }
+
+        let first = analyzer.recv_frame().await.unwrap();
+        let second = analyzer.recv_frame().await.unwrap();
+        assert_eq!((first.pid, first.frametime), (1, Duration::from_millis(16)));
+        assert_eq!(
+            (second.pid, second.frametime),
+            (2, Duration::from_millis(8))
+        );
+    }
+
+    #[test]
+    fn waker_interrupts_blocking_recv() {
+        let mut analyzer = analyzer_with(&[1]);
+        let waker = analyzer.waker();
+
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            waker.wake().unwrap();
+        });
+
+        assert_eq!(analyzer.recv_event(), Some(AnalyzerEvent::Woken));
+        handle.join().unwrap();
+
+        // 唤醒之后不会残留，没有帧时不阻塞的接收立即返回
+        assert_eq!(analyzer.try_recv_event(), None);
+        assert_eq!(analyzer.try_recv(), None);
+
+        analyzer.waker().wake().unwrap();
+        assert_eq!(analyzer.recv_frame(), None);
+    }
+
+    #[test]
+    fn max_timeout_does_not_overflow() {
+        let mut analyzer = analyzer_with(&[1]);
+        for ktime in [0, 16, 32] {
+            let event = analyzer.handle_signal(&signal(1, ktime, 0x1));
+            analyzer.pending.extend(event.map(AnalyzerEvent::Frame));
+        }
+
+        assert!(matches!(
+            analyzer.recv_event_timeout(Duration::MAX),
+            Some(AnalyzerEvent::Frame(_))
+        ));
+        assert_eq!(
+            analyzer.recv_timeout(Duration::MAX),
+            Some((1, Duration::from_millis(16)))
+        );
+    }
+
+    #[test]
+    fn signal_does_not_end_blocking_recv() {
+        extern "C" fn ignore(_: libc::c_int) {}
+
+        let action = libc::sigaction {
+            sa_sigaction: ignore as *const () as usize,
+            sa_mask: unsafe { std::mem::zeroed() },
+            sa_flags: 0,
+            sa_restorer: None,
+        };
+        unsafe { libc::sigaction(libc::SIGUSR1, &raw const action, std::ptr::null_mut()) };
+
+        let mut analyzer = analyzer_with(&[1]);
+        let thread = unsafe { libc::pthread_self() } as usize;
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            unsafe { libc::pthread_kill(thread as libc::pthread_t, libc::SIGUSR1) };
+        });
+
+        let start = Instant::now();
+        assert_eq!(
+            analyzer.recv_event_timeout(Duration::from_millis(100)),
+            None
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn exited_target_is_detached_and_reported() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("0.05")
+            .spawn()
+            .unwrap();
+        let pid = child.id() as Pid;
+
+        let mut analyzer = analyzer_with(&[pid]);
+        let pidfd = PidFd::open(pid).unwrap().unwrap();
+        analyzer.watch(pid, pidfd).unwrap();
+
+        let event = analyzer.recv_event_timeout(Duration::from_secs(5));
+        assert_eq!(event, Some(AnalyzerEvent::TargetExited { pid }));
+        assert!(!analyzer.contains(pid));
+        assert!(analyzer.pidfds.is_empty());
+
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn followed_process_is_reported_after_restart() {
+        let (mut analyzer, _root) = followed_game("restart");
+
+        analyzer.handle_exited(3);
+        assert_eq!(
+            analyzer.try_recv_event(),
+            Some(AnalyzerEvent::TargetExited { pid: 3 })
+        );
+        assert!(analyzer.waiting_restart());
+
+        // 新进程已经被附加，只验证跟随和事件
+        analyzer
+            .map
+            .insert(7, AnalyzeTarget::new(144, IdleThreshold::default()));
+        analyzer.last_scan -= RESCAN_INTERVAL;
+        assert_eq!(
+            analyzer.try_recv_event(),
+            Some(AnalyzerEvent::TargetRestarted { old_pid: 3, pid: 7 })
+        );
+        assert!(!analyzer.waiting_restart());
+        assert_eq!(analyzer.followed["com.example.game"].pid, 7);
+    }
+
+    #[test]
+    fn detaching_exited_process_stops_following() {
+        let mut analyzer = analyzer_with(&[3]);
+        analyzer
+            .followed
+            .insert("com.example.game".to_string(), Followed::new(3));
+
+        analyzer.handle_exited(3);
+        assert!(analyzer.waiting_restart());
+
+        analyzer.detach_app(3).unwrap();
+        assert!(analyzer.followed.is_empty());
+        assert!(!analyzer.waiting_restart());
+    }
+
+    #[test]
+    fn foreground_changes_attach_and_detach_apps() {
+        let root = fake_proc("top-app", &[]);
+        let procs = root.join("cgroup.procs");
+        std::fs::write(&procs, "10\n").unwrap();
+
+        let mut analyzer = AnalyzerBuilder::new()
+            .foreground_procs(&procs)
+            .build()
+            .unwrap();
+        // 已经在监控的pid附加时直接成功，不需要加载eBPF程序
+        for pid in [10, 11, 12] {
+            analyzer
+                .map
+                .insert(pid, AnalyzeTarget::new(144, IdleThreshold::default()));
+        }
+
+        analyzer.follow_foreground().unwrap();
+        assert_eq!(
+            analyzer.try_recv_event(),
+            Some(AnalyzerEvent::ForegroundEntered { pid: 10 })
+        );
+
+        std::fs::write(&procs, "11\n12\n").unwrap();
+        let timeout = Duration::from_secs(5);
+        let events: Vec<_> = (0..3)
+            .filter_map(|_| analyzer.recv_event_timeout(timeout))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                AnalyzerEvent::ForegroundLeft { pid: 10 },
+                AnalyzerEvent::ForegroundEntered { pid: 11 },
+                AnalyzerEvent::ForegroundEntered { pid: 12 },
+            ]
+        );
+        assert!(!analyzer.contains(10));
+        assert!(analyzer.contains(11) && analyzer.contains(12));
+
+        analyzer.stop_following_foreground();
+        std::fs::write(&procs, "").unwrap();
+        assert_eq!(analyzer.recv_event_timeout(Duration::from_millis(50)), None);
+        assert!(analyzer.contains(11));
+    }
+
+    #[test]
+    fn exited_foreground_process_leaves_foreground() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("0.05")
+            .spawn()
+            .unwrap();
+        let pid = child.id() as Pid;
+        let root = fake_proc("top-app-exit", &[]);
+        let procs = root.join("cgroup.procs");
+        std::fs::write(&procs, format!("{pid}\n")).unwrap();
+
+        let mut analyzer = AnalyzerBuilder::new()
+            .foreground_procs(&procs)
+            .build()
+            .unwrap();
+        analyzer
+            .map
+            .insert(pid, AnalyzeTarget::new(144, IdleThreshold::default()));
+        analyzer
+            .watch(pid, PidFd::open(pid).unwrap().unwrap())
+            .unwrap();
+        analyzer.follow_foreground().unwrap();
+        assert_eq!(
+            analyzer.try_recv_event(),
+            Some(AnalyzerEvent::ForegroundEntered { pid })
+        );
+
+        // 进程退出后文件没有被写入，列表中残留的pid也无法再次附加
+        child.wait().unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            analyzer.recv_event_timeout(timeout),
+            Some(AnalyzerEvent::TargetExited { pid })
+        );
+        assert_eq!(
+            analyzer.try_recv_event(),
+            Some(AnalyzerEvent::ForegroundLeft { pid })
+        );
+        assert_eq!(analyzer.try_recv_event(), None);
+        assert!(analyzer.foreground.as_ref().unwrap().pids.is_empty());
+    }
+
+    #[test]
+    fn detached_pid_stops_producing_frames() {
+        let mut analyzer = analyzer_with(&[1, 2]);
+        for (pid, ktime) in [(1, 0), (2, 0), (1, 8), (2, 8)] {
+            let event = analyzer.handle_signal(&signal(pid, ktime, 0x1));
+            analyzer.pending.extend(event.map(AnalyzerEvent::Frame));
+        }
+        assert_eq!(analyzer.pending.len(), 2);
+
+        analyzer.detach_app(1).unwrap();
+
+        assert!(!analyzer.contains(1));
+        assert!(analyzer.pending.iter().all(|event| event.pid() == Some(2)));
+        assert_eq!(frame(&mut analyzer, &signal(1, 16, 0x1)), None);
+        assert_eq!(analyzer.pids().collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! 按(pid, surface)分开保存的状态，统计、目标帧率推断和卡顿判定共用
+
+use std::collections::HashMap;
+
+use crate::{FrameEvent, Pid, SurfaceId};
+
+/// 每个应用的每个surface各自一份状态，并统一决定哪些帧参与分析
+#[derive(Debug, Clone)]
+pub struct PerSurface<T> {
+    include_idle: bool,
+    states: HashMap<(Pid, SurfaceId), T>,
+}
+
+impl<T> Default for PerSurface<T> {
+    fn default() -> Self {
+        Self {
+            include_idle: false,
+            states: HashMap::new(),
+        }
+    }
+}
+
+impl<T> PerSurface<T> {
+    /// 是否分析空闲后恢复的帧，默认不分析
+    pub const fn set_include_idle(&mut self, include: bool) {
+        self.include_idle = include;
+    }
+
+    /// 该帧所在surface的状态，没有时用`new`创建
+    ///
+    /// 可能合并了两帧的帧和默认情况下空闲后恢复的帧不参与分析，返回None
+    pub fn entry(&mut self, event: &FrameEvent, new: impl FnOnce() -> T) -> Option<&mut T> {
+        if event.possibly_merged || (event.resumed_after_idle && !self.include_idle) {
+            return None;
+        }
+
+        Some(
+            self.states
+                .entry((event.pid, event.surface))
+                .or_insert_with(new),
+        )
+    }
+
+    pub fn get(&self, pid: Pid, surface: SurfaceId) -> Option<&T> {
+        self.states.get(&(pid, surface))
+    }
+
+    pub fn get_mut(&mut self, pid: Pid, surface: SurfaceId) -> Option<&mut T> {
+        self.states.get_mut(&(pid, surface))
+    }
+
+    /// 所有有状态的(pid, surface)
+    pub fn surfaces(&self) -> impl Iterator<Item = (Pid, SurfaceId)> + '_ {
+        self.states.keys().copied()
+    }
+
+    /// 删除该应用所有surface的状态
+    pub fn remove(&mut self, pid: Pid) {
+        self.states.retain(|(state_pid, _), _| *state_pid != pid);
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+}
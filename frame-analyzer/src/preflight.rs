@@ -0,0 +1,253 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{ffi::CStr, fmt, fs, io, mem, path::Path};
+
+use crate::{builder::page_size, ebpf::FrameBpf};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_PERFMON: u32 = 38;
+const CAP_BPF: u32 = 39;
+
+/// 运行分析器之前的环境检查，见[`preflight`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreflightReport {
+    /// `uname -r`的原始输出
+    pub kernel_release: String,
+    /// 解析出的(major, minor, patch)
+    pub kernel_version: Option<(u32, u32, u32)>,
+    /// 内核是否支持`BPF_MAP_TYPE_RINGBUF`，没有权限探测时为None
+    ///
+    /// 不支持时使用`PerfEventArray`变体，能否加载体现在`verifier_error`中
+    pub ringbuf: Option<bool>,
+    pub uprobe: UprobeSupport,
+    /// 当前进程的有效权限
+    pub capabilities: Capabilities,
+    /// `RLIMIT_MEMLOCK`的当前值(字节)，None表示不限制
+    pub memlock: Option<u64>,
+    /// 加载内置eBPF程序的错误，None表示通过了verifier
+    pub verifier_error: Option<String>,
+}
+
+/// 内核提供uprobe的方式，任意一种可用即可
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UprobeSupport {
+    /// perf的uprobe PMU
+    pub perf: bool,
+    /// tracefs的`uprobe_events`
+    pub tracefs: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub bpf: bool,
+    pub perfmon: bool,
+    pub sys_admin: bool,
+}
+
+impl PreflightReport {
+    /// 所有必需的条件都满足
+    #[must_use]
+    pub const fn is_ready(&self) -> bool {
+        (self.uprobe.perf || self.uprobe.tracefs)
+            && (self.capabilities.sys_admin
+                || (self.capabilities.bpf && self.capabilities.perfmon))
+            && self.verifier_error.is_none()
+    }
+}
+
+/// 检查当前环境能否运行分析器，不需要先创建[`Analyzer`](crate::Analyzer)
+///
+/// 会尝试加载一次内置的eBPF程序，然后立即卸载
+#[must_use]
+pub fn preflight() -> PreflightReport {
+    let kernel_release = kernel_release().unwrap_or_default();
+    let caps = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| effective_caps(&status))
+        .unwrap_or(0);
+    let has_cap = |cap: u32| caps & (1 << cap) != 0;
+
+    PreflightReport {
+        kernel_version: parse_kernel_version(&kernel_release),
+        kernel_release,
+        ringbuf: ringbuf_supported(),
+        uprobe: UprobeSupport {
+            perf: Path::new("/sys/bus/event_source/devices/uprobe/type").exists(),
+            tracefs: ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"]
+                .iter()
+                .any(|tracefs| Path::new(tracefs).join("uprobe_events").exists()),
+        },
+        capabilities: Capabilities {
+            bpf: has_cap(CAP_BPF),
+            perfmon: has_cap(CAP_PERFMON),
+            sys_admin: has_cap(CAP_SYS_ADMIN),
+        },
+        memlock: memlock(),
+        verifier_error: FrameBpf::load(page_size()).err().map(|err| err.to_string()),
+    }
+}
+
+/// 创建一个最小的ringbuf map来探测内核是否支持，没有权限时无法判断
+pub fn ringbuf_supported() -> Option<bool> {
+    // 内核要求大小是页大小的2的幂次倍，16KiB页的内核上4KiB会返回EINVAL
+    create_ringbuf(page_size())
+}
+
+fn create_ringbuf(max_entries: u32) -> Option<bool> {
+    // bpf_attr中创建map用到的前几个字段，其余保持为0
+    #[repr(C)]
+    struct MapCreateAttr {
+        map_type: u32,
+        key_size: u32,
+        value_size: u32,
+        max_entries: u32,
+        rest: [u32; 28],
+    }
+
+    let attr = MapCreateAttr {
+        map_type: BPF_MAP_TYPE_RINGBUF,
+        key_size: 0,
+        value_size: 0,
+        max_entries,
+        rest: [0; 28],
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_MAP_CREATE,
+            &raw const attr,
+            mem::size_of::<MapCreateAttr>(),
+        )
+    };
+
+    if fd >= 0 {
+        unsafe { libc::close(fd as libc::c_int) };
+        return Some(true);
+    }
+
+    match io::Error::last_os_error().raw_os_error() {
+        Some(libc::EINVAL | libc::ENOSYS) => Some(false),
+        _ => None,
+    }
+}
+
+fn kernel_release() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&raw mut uts) } != 0 {
+        return None;
+    }
+
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+// 例如`5.10.198-android12-9-g3c6a5d4`、`6.18.44-fc-v130`
+fn parse_kernel_version(release: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let patch = parts.next().and_then(|patch| patch.parse().ok()).unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+fn effective_caps(status: &str) -> Option<u64> {
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+fn memlock() -> Option<u64> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &raw mut rlim) } != 0
+        || rlim.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+
+    Some(rlim.rlim_cur)
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+        writeln!(f, "kernel:         {}", self.kernel_release)?;
+        match self.ringbuf {
+            Some(ringbuf) => writeln!(f, "ringbuf:        {}", yes_no(ringbuf))?,
+            None => writeln!(f, "ringbuf:        unknown")?,
+        }
+        writeln!(
+            f,
+            "uprobe:         perf {}, tracefs {}",
+            yes_no(self.uprobe.perf),
+            yes_no(self.uprobe.tracefs)
+        )?;
+        writeln!(
+            f,
+            "capabilities:   CAP_BPF {}, CAP_PERFMON {}, CAP_SYS_ADMIN {}",
+            yes_no(self.capabilities.bpf),
+            yes_no(self.capabilities.perfmon),
+            yes_no(self.capabilities.sys_admin)
+        )?;
+        match self.memlock {
+            Some(bytes) => writeln!(f, "memlock:        {bytes} bytes")?,
+            None => writeln!(f, "memlock:        unlimited")?,
+        }
+        match &self.verifier_error {
+            Some(error) => writeln!(f, "verifier:       failed, {error}")?,
+            None => writeln!(f, "verifier:       passed")?,
+        }
+        write!(f, "ready:          {}", yes_no(self.is_ready()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_release_is_parsed() {
+        assert_eq!(parse_kernel_version("5.10.198-android12-9-g3c6a5d4"), Some((5, 10, 198)));
+        assert_eq!(parse_kernel_version("4.19.157-perf+"), Some((4, 19, 157)));
+        assert_eq!(parse_kernel_version("6.1-rc3"), Some((6, 1, 0)));
+        assert_eq!(parse_kernel_version(""), None);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_BPF and a 5.8+ kernel"]
+    fn ringbuf_probe_uses_page_size() {
+        assert_eq!(create_ringbuf(page_size()), Some(true));
+        // 不是页大小倍数的大小会被拒绝，不能当成内核不支持ring buffer的依据
+        assert_eq!(create_ringbuf(page_size() / 2), Some(false));
+        assert_eq!(ringbuf_supported(), Some(true));
+    }
+
+    #[test]
+    fn effective_caps_are_read_from_status() {
+        let status = "Name:\tcat\nCapInh:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+        let caps = effective_caps(status).unwrap();
+        assert_ne!(caps & (1 << CAP_BPF), 0);
+        assert_eq!(effective_caps("Name:\tcat\n"), None);
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{Pid, uprobe::UprobeHandler};
+
+/// 按名称跟随的进程
+pub struct Followed {
+    /// 最近一次附加的pid
+    pub pid: Pid,
+    /// 进程已经退出，等待以新pid重新出现
+    pub exited: bool,
+    /// 退出进程留下的探针，重新附加时复用其中缓存的库路径
+    pub uprobe: Option<UprobeHandler>,
+}
+
+impl Followed {
+    pub const fn new(pid: Pid) -> Self {
+        Self {
+            pid,
+            exited: false,
+            uprobe: None,
+        }
+    }
+}
+
+/// 扫描`<proc_root>/*/cmdline`，返回进程名匹配的最小pid
+///
+/// 安卓应用的进程名就是包名，普通程序也可以用可执行文件名匹配
+pub fn find_process(proc_root: &Path, name: &str) -> Option<Pid> {
+    fs::read_dir(proc_root)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let pid = entry.file_name().to_str()?.parse::<Pid>().ok()?;
+            let cmdline = fs::read(entry.path().join("cmdline")).ok()?;
+            process_name_matches(&cmdline, name).then_some(pid)
+        })
+        .min()
+}
+
+/// 从`<proc_root>/<pid>/maps`中找到进程实际映射的库，例如`libgui.so`
+///
+/// 映射路径在当前挂载命名空间中指向同一个文件时直接返回，否则通过`<proc_root>/<pid>/root`访问
+pub fn mapped_library(proc_root: &Path, pid: Pid, name: &str) -> Option<PathBuf> {
+    let process = proc_root.join(pid.to_string());
+    let maps = fs::read_to_string(process.join("maps")).ok()?;
+
+    let mapped = maps.lines().find_map(|line| {
+        // 地址 权限 偏移 设备 inode 路径，路径本身可能包含空格
+        let path = line.splitn(6, ' ').nth(5)?.trim_start();
+        let path = Path::new(path);
+        (path.is_absolute() && path.file_name()? == name).then_some(path)
+    })?;
+
+    let rooted = process.join("root").join(mapped.strip_prefix("/").ok()?);
+    let rooted_meta = fs::metadata(&rooted).ok()?;
+    match fs::metadata(mapped) {
+        Ok(meta) if meta.dev() == rooted_meta.dev() && meta.ino() == rooted_meta.ino() => {
+            Some(mapped.to_path_buf())
+        }
+        _ => Some(rooted),
+    }
+}
+
+fn process_name_matches(cmdline: &[u8], name: &str) -> bool {
+    let arg0 = cmdline.split(|byte| *byte == 0).next().unwrap_or_default();
+    let Ok(arg0) = std::str::from_utf8(arg0) else {
+        return false;
+    };
+
+    arg0 == name || Path::new(arg0).file_name().is_some_and(|file| file == name)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::ops::Deref;
+
+    use super::*;
+
+    /// 临时的假procfs目录，离开作用域时删除，测试失败时也不会残留
+    pub struct FakeProc(PathBuf);
+
+    impl Deref for FakeProc {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// 在临时目录下为每个进程创建只有cmdline的目录
+    pub fn fake_proc(name: &str, processes: &[(&str, &[u8])]) -> FakeProc {
+        let root =
+            std::env::temp_dir().join(format!("frame-analyzer-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        for (dir, cmdline) in processes {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("cmdline"), cmdline).unwrap();
+        }
+        FakeProc(root)
+    }
+
+    #[test]
+    fn mapped_library_is_opened_through_process_root() {
+        let root = fake_proc(
+            "maps",
+            &[
+                ("42", b"com.example.game\0"),
+                ("43", b"com.example.other\0"),
+            ],
+        );
+        let maps = "\
+70000000-70001000 r--p 00000000 fd:00 100 /apex/com.android.art/lib64/libart.so
+71000000-71100000 r-xp 00000000 fd:00 200 /system_ext/lib64/libgui.so
+72000000-72001000 rw-p 00000000 00:00 0 [anon:libc_malloc]
+";
+        fs::write(root.join("42/maps"), maps).unwrap();
+        fs::create_dir_all(root.join("42/root/system_ext/lib64")).unwrap();
+        fs::write(root.join("42/root/system_ext/lib64/libgui.so"), b"").unwrap();
+        fs::write(root.join("43/maps"), maps.replace("libgui.so", "libfoo.so")).unwrap();
+
+        assert_eq!(
+            mapped_library(&root, 42, "libgui.so"),
+            Some(root.join("42/root/system_ext/lib64/libgui.so"))
+        );
+        assert_eq!(mapped_library(&root, 43, "libgui.so"), None);
+        assert_eq!(mapped_library(&root, 44, "libgui.so"), None);
+    }
+
+    #[test]
+    fn finds_process_by_package_or_executable_name() {
+        let root = fake_proc(
+            "find",
+            &[
+                ("1", b"/init\0second_stage\0"),
+                ("self", b"/bin/cat\0"),
+                ("200", b"com.example.game:remote\0"),
+                ("301", b"com.example.game\0"),
+                ("120", b"com.example.game\0"),
+                ("400", b"/system/bin/surfaceflinger\0"),
+                ("500", b""),
+            ],
+        );
+
+        assert_eq!(find_process(&root, "com.example.game"), Some(120));
+        assert_eq!(find_process(&root, "surfaceflinger"), Some(400));
+        assert_eq!(find_process(&root, "init"), Some(1));
+        assert_eq!(find_process(&root, "com.example"), None);
+        assert_eq!(find_process(&root.join("missing"), "init"), None);
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{fmt, io, path::PathBuf};
+
+use aya::programs::{ProgramError, uprobe::UProbeError};
+
+use crate::{ErrorKind, Pid};
+
+/// 附加uprobe时尝试过的所有库和符号，失败时放在错误中，成功时可以通过
+/// [`Analyzer::attach_report`](crate::Analyzer::attach_report)查询
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttachReport {
+    /// 目标应用，None表示附加到所有进程
+    pub pid: Option<Pid>,
+    /// 按尝试顺序排列
+    pub attempts: Vec<AttachAttempt>,
+}
+
+/// 一次附加尝试
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachAttempt {
+    pub library: PathBuf,
+    /// 库文件不存在时为None
+    pub symbol: Option<String>,
+    /// 符号在库文件中的偏移
+    ///
+    /// 库无法解析或者符号表中没有该符号时为None，这时附加通常也会因为找不到符号而失败
+    pub offset: Option<u64>,
+    /// None表示附加成功
+    pub failure: Option<AttachFailure>,
+}
+
+/// 附加失败的原因
+///
+/// 以后可能新增更细的原因，需要按类别处理时匹配[`AttachFailure::kind`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AttachFailure {
+    FileMissing,
+    SymbolMissing,
+    PermissionDenied,
+    /// 目标进程已经退出
+    PidGone,
+    /// 其它错误，保留原始信息
+    Other(String),
+}
+
+impl AttachReport {
+    pub(crate) const fn new(pid: Option<Pid>) -> Self {
+        Self {
+            pid,
+            attempts: Vec::new(),
+        }
+    }
+
+    /// 附加成功的尝试
+    pub fn attached(&self) -> impl Iterator<Item = &AttachAttempt> {
+        self.attempts
+            .iter()
+            .filter(|attempt| attempt.failure.is_none())
+    }
+
+    pub(crate) fn file_missing(&mut self, library: PathBuf) {
+        self.attempts.push(AttachAttempt {
+            library,
+            symbol: None,
+            offset: None,
+            failure: Some(AttachFailure::FileMissing),
+        });
+    }
+}
+
+impl AttachFailure {
+    /// 失败原因的大致分类，与[`AnalyzerError::kind`](crate::AnalyzerError::kind)使用同样的类别
+    #[must_use]
+    pub const fn kind(&self) -> ErrorKind {
+        match self {
+            Self::FileMissing | Self::SymbolMissing => ErrorKind::NotFound,
+            Self::PermissionDenied => ErrorKind::PermissionDenied,
+            Self::PidGone => ErrorKind::TargetGone,
+            Self::Other(_) => ErrorKind::Other,
+        }
+    }
+
+    pub(crate) fn from_program_error(error: &ProgramError) -> Self {
+        match error {
+            ProgramError::UProbeError(UProbeError::SymbolError { .. }) => Self::SymbolMissing,
+            ProgramError::UProbeError(UProbeError::InvalidTarget { .. }) => Self::FileMissing,
+            ProgramError::UProbeError(UProbeError::FileError { io_error, .. }) => {
+                Self::from_io_error(io_error).unwrap_or_else(|| Self::Other(error.to_string()))
+            }
+            ProgramError::SyscallError(syscall) => Self::from_io_error(&syscall.io_error)
+                .unwrap_or_else(|| Self::Other(error.to_string())),
+            _ => Self::Other(error.to_string()),
+        }
+    }
+
+    fn from_io_error(error: &io::Error) -> Option<Self> {
+        match error.raw_os_error()? {
+            libc::ENOENT => Some(Self::FileMissing),
+            libc::EPERM | libc::EACCES => Some(Self::PermissionDenied),
+            libc::ESRCH => Some(Self::PidGone),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AttachFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileMissing => write!(f, "file missing"),
+            Self::SymbolMissing => write!(f, "symbol missing"),
+            Self::PermissionDenied => write!(f, "permission denied"),
+            Self::PidGone => write!(f, "pid gone"),
+            Self::Other(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl fmt::Display for AttachReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "attach report for pid {pid}:")?,
+            None => write!(f, "attach report for all processes:")?,
+        }
+
+        if self.attempts.is_empty() {
+            return write!(f, " no candidate library");
+        }
+
+        for attempt in &self.attempts {
+            write!(f, "\n  {}", attempt.library.display())?;
+            if let Some(symbol) = &attempt.symbol {
+                write!(f, " {symbol}")?;
+            }
+            if let Some(offset) = attempt.offset {
+                write!(f, "@{offset:#x}")?;
+            }
+            match &attempt.failure {
+                Some(failure) => write!(f, ": {failure}")?,
+                None => write!(f, ": attached")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aya::sys::SyscallError;
+
+    use super::*;
+
+    fn syscall(errno: i32) -> ProgramError {
+        ProgramError::SyscallError(SyscallError {
+            call: "perf_event_open",
+            io_error: io::Error::from_raw_os_error(errno),
+        })
+    }
+
+    #[test]
+    fn aya_errors_are_classified() {
+        let symbol = ProgramError::UProbeError(UProbeError::SymbolError {
+            symbol: "queueBuffer".into(),
+            error: "unknown symbol".into(),
+        });
+        let file = ProgramError::UProbeError(UProbeError::FileError {
+            filename: "/system/lib64/libgui.so".into(),
+            io_error: io::Error::from_raw_os_error(libc::EACCES),
+        });
+
+        assert_eq!(AttachFailure::from_program_error(&symbol), AttachFailure::SymbolMissing);
+        assert_eq!(AttachFailure::from_program_error(&file), AttachFailure::PermissionDenied);
+        assert_eq!(AttachFailure::from_program_error(&syscall(libc::ESRCH)), AttachFailure::PidGone);
+        assert_eq!(AttachFailure::from_program_error(&syscall(libc::EPERM)), AttachFailure::PermissionDenied);
+        assert!(matches!(
+            AttachFailure::from_program_error(&syscall(libc::EINVAL)),
+            AttachFailure::Other(_)
+        ));
+    }
+}
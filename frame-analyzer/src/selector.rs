@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! 主surface的选择策略
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::SurfaceId;
+
+/// 从应用的所有surface中选出主surface
+///
+/// 每记录一帧都会调用一次，实现应当足够轻量
+pub trait SurfaceSelector: Send {
+    fn select(&self, surfaces: &[SurfaceView<'_>]) -> Option<SurfaceId>;
+}
+
+/// 选择器看到的一个surface
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceView<'a> {
+    id: SurfaceId,
+    frames: u64,
+    last_ktime_ns: u64,
+    history: &'a VecDeque<Duration>,
+}
+
+impl<'a> SurfaceView<'a> {
+    pub(crate) const fn new(
+        id: SurfaceId,
+        frames: u64,
+        last_ktime_ns: u64,
+        history: &'a VecDeque<Duration>,
+    ) -> Self {
+        Self {
+            id,
+            frames,
+            last_ktime_ns,
+            history,
+        }
+    }
+
+    #[must_use]
+    pub const fn id(&self) -> SurfaceId {
+        self.id
+    }
+
+    /// 开始监控以来该surface提交的帧数
+    #[must_use]
+    pub const fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// 最后一次提交帧的`CLOCK_MONOTONIC`时间戳(ns)
+    #[must_use]
+    pub const fn last_ktime_ns(&self) -> u64 {
+        self.last_ktime_ns
+    }
+
+    /// 最近的帧时间，最新的在前
+    pub fn history(&self) -> impl Iterator<Item = Duration> + 'a {
+        self.history.iter().copied()
+    }
+
+    #[must_use]
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// 按最近的帧时间历史计算的帧率，历史为空时为0
+    #[must_use]
+    pub fn recent_fps(&self) -> f64 {
+        let total = self.history().sum::<Duration>().as_secs_f64();
+        if total > 0.0 {
+            self.history.len() as f64 / total
+        } else {
+            0.0
+        }
+    }
+}
+
+/// 默认策略：历史最长的surface中帧时间总和最小的那个
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LongestHistory;
+
+impl SurfaceSelector for LongestHistory {
+    fn select(&self, surfaces: &[SurfaceView<'_>]) -> Option<SurfaceId> {
+        let max_len = surfaces
+            .iter()
+            .map(SurfaceView::history_len)
+            .max()
+            .unwrap_or_default();
+        surfaces
+            .iter()
+            .filter(|surface| surface.history_len() == max_len)
+            .min_by_key(|surface| surface.history().sum::<Duration>())
+            .map(SurfaceView::id)
+    }
+}
+
+/// 最近帧率最高的surface，帧率相同时取帧数多的
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HighestRecentRate;
+
+impl SurfaceSelector for HighestRecentRate {
+    fn select(&self, surfaces: &[SurfaceView<'_>]) -> Option<SurfaceId> {
+        surfaces
+            .iter()
+            .max_by(|a, b| {
+                a.recent_fps()
+                    .total_cmp(&b.recent_fps())
+                    .then(a.frames().cmp(&b.frames()))
+            })
+            .map(SurfaceView::id)
+    }
+}
+
+/// 最近一次提交帧的surface
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MostRecentlyActive;
+
+impl SurfaceSelector for MostRecentlyActive {
+    fn select(&self, surfaces: &[SurfaceView<'_>]) -> Option<SurfaceId> {
+        surfaces
+            .iter()
+            .max_by_key(|surface| surface.last_ktime_ns())
+            .map(SurfaceView::id)
+    }
+}
+
+/// 固定选择某个surface，该surface没有出现时没有主surface
+#[derive(Debug, Clone, Copy)]
+pub struct PinnedSurface(pub SurfaceId);
+
+impl SurfaceSelector for PinnedSurface {
+    fn select(&self, surfaces: &[SurfaceView<'_>]) -> Option<SurfaceId> {
+        surfaces
+            .iter()
+            .any(|surface| surface.id() == self.0)
+            .then_some(self.0)
+    }
+}
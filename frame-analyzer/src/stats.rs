@@ -0,0 +1,334 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! 帧时间统计：滚动窗口的FPS、均值、标准差、极值和百分位
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{FrameEvent, Pid, SurfaceId, per_surface::PerSurface};
+
+/// 滚动窗口的范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsWindow {
+    /// 最近的若干帧，至少为1
+    Frames(usize),
+    /// 帧时间之和不超过这段时间的最近若干帧，至少保留一帧
+    Duration(Duration),
+}
+
+impl Default for StatsWindow {
+    fn default() -> Self {
+        Self::Frames(120)
+    }
+}
+
+/// 一组帧时间的统计结果
+///
+/// 百分位是帧时间的百分位，P99越大说明越卡；
+/// 1% low是最慢的1%帧(至少一帧)的平均帧时间换算成的FPS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub frames: usize,
+    /// 帧数除以帧时间之和
+    pub fps: f64,
+    pub mean: Duration,
+    /// 帧时间的总体标准差
+    pub std_dev: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub low_1_fps: f64,
+    pub low_0_1_fps: f64,
+}
+
+impl FrameStats {
+    /// 对任意帧时间序列做统计，序列为空或者总时间为0时返回None
+    pub fn from_frametimes<I>(frametimes: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = Duration>,
+    {
+        let mut sorted: Vec<_> = frametimes.into_iter().collect();
+        sorted.sort_unstable();
+
+        let total: Duration = sorted.iter().sum();
+        if total.is_zero() {
+            return None;
+        }
+
+        let frames = sorted.len();
+        let mean = total / frames as u32;
+        let mean_secs = mean.as_secs_f64();
+        let variance = sorted
+            .iter()
+            .map(|frametime| (frametime.as_secs_f64() - mean_secs).powi(2))
+            .sum::<f64>()
+            / frames as f64;
+
+        Some(Self {
+            frames,
+            fps: frames as f64 / total.as_secs_f64(),
+            mean,
+            std_dev: Duration::from_secs_f64(variance.sqrt()),
+            min: sorted[0],
+            max: sorted[frames - 1],
+            p50: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+            low_1_fps: low_fps(&sorted, 1.0),
+            low_0_1_fps: low_fps(&sorted, 0.1),
+        })
+    }
+}
+
+// nearest-rank，sorted非空且升序
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn low_fps(sorted: &[Duration], percent: f64) -> f64 {
+    let count = ((percent / 100.0 * sorted.len() as f64).ceil() as usize).max(1);
+    let slowest = &sorted[sorted.len() - count..];
+    let total: Duration = slowest.iter().sum();
+    if total.is_zero() {
+        0.0
+    } else {
+        count as f64 / total.as_secs_f64()
+    }
+}
+
+/// 滚动窗口中的帧时间
+#[derive(Debug, Clone)]
+pub struct FrameWindow {
+    window: StatsWindow,
+    frametimes: VecDeque<Duration>,
+    total: Duration,
+}
+
+impl FrameWindow {
+    #[must_use]
+    pub const fn new(window: StatsWindow) -> Self {
+        Self {
+            window,
+            frametimes: VecDeque::new(),
+            total: Duration::ZERO,
+        }
+    }
+
+    pub fn push(&mut self, frametime: Duration) {
+        self.frametimes.push_back(frametime);
+        self.total += frametime;
+
+        while self.frametimes.len() > 1 && self.is_over() {
+            if let Some(oldest) = self.frametimes.pop_front() {
+                self.total -= oldest;
+            }
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        match self.window {
+            StatsWindow::Frames(frames) => self.frametimes.len() > frames.max(1),
+            StatsWindow::Duration(duration) => self.total > duration,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frametimes.clear();
+        self.total = Duration::ZERO;
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.frametimes.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.frametimes.is_empty()
+    }
+
+    /// 按时间顺序的帧时间，最早的在前
+    #[must_use]
+    pub fn frametimes(&self) -> impl ExactSizeIterator<Item = Duration> + '_ {
+        self.frametimes.iter().copied()
+    }
+
+    /// 窗口内的平均FPS，不需要排序，适合每帧调用
+    #[must_use]
+    pub fn fps(&self) -> Option<f64> {
+        if self.total.is_zero() {
+            None
+        } else {
+            Some(self.frametimes.len() as f64 / self.total.as_secs_f64())
+        }
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> Option<FrameStats> {
+        FrameStats::from_frametimes(self.frametimes())
+    }
+}
+
+/// 每个应用的每个surface各自一个滚动窗口
+///
+/// [`Analyzer`](crate::Analyzer)会自动记录它上报的帧，也可以单独使用
+#[derive(Debug, Clone, Default)]
+pub struct SurfaceStats {
+    window: StatsWindow,
+    windows: PerSurface<FrameWindow>,
+}
+
+impl SurfaceStats {
+    #[must_use]
+    pub fn new(window: StatsWindow) -> Self {
+        Self {
+            window,
+            windows: PerSurface::default(),
+        }
+    }
+
+    /// 是否统计空闲后恢复的帧，默认不统计
+    pub const fn set_include_idle(&mut self, include: bool) {
+        self.windows.set_include_idle(include);
+    }
+
+    /// 记录一帧，可能合并了两帧的帧和默认情况下空闲后恢复的帧不计入统计，返回是否记录
+    pub fn push(&mut self, event: &FrameEvent) -> bool {
+        let Some(window) = self.windows.entry(event, || FrameWindow::new(self.window)) else {
+            return false;
+        };
+
+        window.push(event.frametime);
+        true
+    }
+
+    #[must_use]
+    pub fn window(&self, pid: Pid, surface: SurfaceId) -> Option<&FrameWindow> {
+        self.windows.get(pid, surface)
+    }
+
+    #[must_use]
+    pub fn stats(&self, pid: Pid, surface: SurfaceId) -> Option<FrameStats> {
+        self.window(pid, surface)?.stats()
+    }
+
+    /// 所有记录过帧的(pid, surface)
+    pub fn surfaces(&self) -> impl Iterator<Item = (Pid, SurfaceId)> + '_ {
+        self.windows.surfaces()
+    }
+
+    /// 删除该应用所有surface的窗口
+    pub fn remove(&mut self, pid: Pid) {
+        self.windows.remove(pid);
+    }
+
+    pub fn clear(&mut self) {
+        self.windows.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn stats_of_frametimes() {
+        // 99帧10ms，1帧50ms
+        let frametimes = std::iter::repeat_n(ms(10), 99).chain([ms(50)]);
+        let stats = FrameStats::from_frametimes(frametimes).unwrap();
+
+        assert_eq!(stats.frames, 100);
+        assert!((stats.fps - 100.0 / 1.04).abs() < 1e-9);
+        assert_eq!(stats.mean, Duration::from_micros(10_400));
+        assert_eq!(stats.min, ms(10));
+        assert_eq!(stats.max, ms(50));
+        assert_eq!(stats.p50, ms(10));
+        assert_eq!(stats.p99, ms(10));
+        assert!((stats.low_1_fps - 20.0).abs() < 1e-9);
+        assert!((stats.low_0_1_fps - 20.0).abs() < 1e-9);
+        // 方差为0.99 * 0.4^2 + 0.01 * 39.6^2 = 15.84 ms^2
+        assert!((stats.std_dev.as_secs_f64() - 15.84e-6_f64.sqrt()).abs() < 1e-9);
+
+        assert!(FrameStats::from_frametimes([]).is_none());
+        assert!(FrameStats::from_frametimes([Duration::ZERO]).is_none());
+    }
+
+    #[test]
+    fn window_by_frames_and_by_time() {
+        let mut frames = FrameWindow::new(StatsWindow::Frames(3));
+        for frametime in [ms(10), ms(20), ms(30), ms(40)] {
+            frames.push(frametime);
+        }
+        assert_eq!(frames.frametimes().collect::<Vec<_>>(), [ms(20), ms(30), ms(40)]);
+        assert!((frames.fps().unwrap() - 1000.0 / 30.0).abs() < 1e-9);
+
+        let mut time = FrameWindow::new(StatsWindow::Duration(ms(50)));
+        for frametime in [ms(10), ms(20), ms(30)] {
+            time.push(frametime);
+        }
+        assert_eq!(time.frametimes().collect::<Vec<_>>(), [ms(20), ms(30)]);
+
+        // 单帧超过窗口时仍然保留
+        time.push(ms(100));
+        assert_eq!(time.frametimes().collect::<Vec<_>>(), [ms(100)]);
+    }
+
+    #[test]
+    fn merged_and_idle_frames_are_skipped() {
+        let event = |surface, frametime, possibly_merged| FrameEvent {
+            pid: 1,
+            surface,
+            ktime_ns: 0,
+            frametime,
+            possibly_merged,
+            resumed_after_idle: false,
+            is_main_surface: true,
+            tid: 1,
+            cpu: 0,
+            comm: [0; 16],
+        };
+
+        let mut stats = SurfaceStats::new(StatsWindow::default());
+        assert!(stats.push(&event(1, ms(10), false)));
+        assert!(!stats.push(&event(1, ms(40), true)));
+        assert!(stats.push(&event(2, ms(20), false)));
+
+        let idle = FrameEvent {
+            resumed_after_idle: true,
+            ..event(1, ms(5000), false)
+        };
+        assert!(!stats.push(&idle));
+        stats.set_include_idle(true);
+        assert!(stats.push(&idle));
+
+        assert_eq!(stats.stats(1, 1).unwrap().max, ms(5000));
+        assert_eq!(stats.window(1, 2).unwrap().len(), 1);
+
+        stats.remove(1);
+        assert_eq!(stats.surfaces().count(), 0);
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{fs, path::Path};
+
+use cpp_demangle::{DemangleOptions, Symbol};
+use object::{Object, ObjectSection, ObjectSymbol};
+
+const QUEUE_BUFFER: &str = "android::Surface::queueBuffer(";
+
+/// 库中所有`android::Surface::queueBuffer`重载的原始符号名和文件偏移
+#[derive(Debug, Default)]
+pub struct LibrarySymbols {
+    queue_buffer: Vec<(String, u64)>,
+    // 调用者指定的其它符号，按名字而不是按demangle的结果查找
+    known: Vec<(String, u64)>,
+}
+
+impl LibrarySymbols {
+    /// 解析`.dynsym`和`.symtab`，文件无法读取或者不是ELF时为空
+    ///
+    /// `known`中的符号即使无法demangle成queueBuffer也会记录偏移
+    pub fn read(path: &Path, known: &[String]) -> Self {
+        let Ok(data) = fs::read(path) else {
+            return Self::default();
+        };
+        let Ok(file) = object::File::parse(data.as_slice()) else {
+            return Self::default();
+        };
+
+        let mut symbols = Self::default();
+        for symbol in file.dynamic_symbols().chain(file.symbols()) {
+            if !symbol.is_definition() {
+                continue;
+            }
+            let Ok(name) = symbol.name() else {
+                continue;
+            };
+
+            let found = if is_queue_buffer(name) {
+                &mut symbols.queue_buffer
+            } else if known.iter().any(|known| known == name) {
+                &mut symbols.known
+            } else {
+                continue;
+            };
+            if !found.iter().any(|(found, _)| found == name)
+                && let Some(offset) = file_offset(&file, &symbol)
+            {
+                found.push((name.to_string(), offset));
+            }
+        }
+
+        symbols
+    }
+
+    pub fn queue_buffer(&self) -> impl Iterator<Item = &str> {
+        self.queue_buffer.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn offset(&self, name: &str) -> Option<u64> {
+        self.queue_buffer
+            .iter()
+            .chain(&self.known)
+            .find(|(found, _)| found == name)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+// 与aya的计算方式相同：虚拟地址换算为所在节的文件偏移
+fn file_offset(file: &object::File<'_>, symbol: &object::Symbol<'_, '_>) -> Option<u64> {
+    let section = file.section_by_index(symbol.section_index()?).ok()?;
+    let (section_offset, _) = section.file_range()?;
+    Some(symbol.address() - section.address() + section_offset)
+}
+
+fn is_queue_buffer(name: &str) -> bool {
+    Symbol::new(name)
+        .ok()
+        .and_then(|symbol| symbol.demangle(&DemangleOptions::default()).ok())
+        .is_some_and(|demangled| demangled.starts_with(QUEUE_BUFFER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uprobe::ProbeConfig;
+
+    #[test]
+    fn known_overloads_demangle_to_queue_buffer() {
+        for symbol in ProbeConfig::default().symbols {
+            assert!(is_queue_buffer(&symbol), "{symbol}");
+        }
+
+        // 其它成员函数、其它类和无法解析的符号都不算
+        assert!(!is_queue_buffer("_ZN7android7Surface13dequeueBufferEPP19ANativeWindowBufferPi"));
+        assert!(!is_queue_buffer("_ZN7android19BufferQueueProducer11queueBufferEiRKNS_22IGraphicBufferProducer16QueueBufferInputEPNS1_17QueueBufferOutputE"));
+        assert!(!is_queue_buffer("queueBuffer"));
+        assert!(!is_queue_buffer(""));
+    }
+
+    #[test]
+    fn unreadable_library_has_no_symbols() {
+        for path in ["/nonexistent/libgui.so", "/proc/self/cmdline"] {
+            let symbols = LibrarySymbols::read(Path::new(path), &[]);
+            assert_eq!(symbols.queue_buffer().count(), 0);
+        }
+    }
+
+    #[unsafe(no_mangle)]
+    #[inline(never)]
+    extern "C" fn frame_analyzer_test_known_symbol() {}
+
+    #[test]
+    fn known_symbols_are_resolved() {
+        let known = [
+            "frame_analyzer_test_known_symbol".to_string(),
+            "frame_analyzer_test_missing_symbol".to_string(),
+        ];
+        // 调用一次，避免链接时被当作未使用的函数丢弃
+        frame_analyzer_test_known_symbol();
+        let symbols = LibrarySymbols::read(Path::new("/proc/self/exe"), &known);
+
+        assert!(symbols.queue_buffer().all(|name| name != known[0]));
+        assert!(symbols.offset(&known[0]).is_some());
+        assert_eq!(symbols.offset(&known[1]), None);
+    }
+}
@@ -0,0 +1,388 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! 从帧时间推断应用的目标帧率和屏幕刷新率
+//!
+//! 目标帧率取帧时间中位数对应的帧率，向上贴近最接近的候选帧率；
+//! 帧只能在vsync时上屏，帧时间会聚成vsync周期整数倍的几簇，
+//! 刷新率取能同时整除所有簇的最长vsync周期对应的候选帧率，不低于目标帧率
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{FrameEvent, Pid, SurfaceId, per_surface::PerSurface};
+
+// 默认的候选帧率和命中误差，Jank判定推断vsync时也使用
+pub(crate) const DEFAULT_CANDIDATES: [u32; 7] = [30, 45, 60, 90, 120, 144, 165];
+pub(crate) const DEFAULT_TOLERANCE: f64 = 0.1;
+
+/// 一次推断的结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TargetFps {
+    /// 应用的帧率上限
+    pub fps: u32,
+    /// 推断的屏幕刷新率，即vsync周期对应的帧率
+    ///
+    /// 帧时间只有一簇时无法区分，与`fps`相同
+    pub refresh_rate: u32,
+    /// 窗口内帧时间落在目标帧时间附近的比例，0到1
+    pub confidence: f32,
+}
+
+impl TargetFps {
+    /// 刷新率对应的vsync周期
+    #[must_use]
+    pub fn vsync(&self) -> Duration {
+        Duration::from_secs(1)
+            .checked_div(self.refresh_rate)
+            .unwrap_or_default()
+    }
+}
+
+/// 推断的参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetFpsConfig {
+    /// 候选帧率，升序
+    pub candidates: Vec<u32>,
+    /// 参与推断的最近帧数
+    pub window: usize,
+    /// 窗口内至少有这么多帧才开始推断
+    pub min_frames: usize,
+    /// 新的目标帧率或刷新率连续这么多帧不变才会切换
+    pub hold_frames: usize,
+    /// 帧时间与目标帧时间的相对误差在此范围内算作命中
+    pub tolerance: f64,
+    /// 置信度低于此值时不切换
+    pub min_confidence: f32,
+}
+
+impl Default for TargetFpsConfig {
+    fn default() -> Self {
+        Self {
+            candidates: DEFAULT_CANDIDATES.to_vec(),
+            window: 120,
+            min_frames: 60,
+            hold_frames: 30,
+            tolerance: DEFAULT_TOLERANCE,
+            min_confidence: 0.5,
+        }
+    }
+}
+
+/// 对一个surface的帧时间序列推断目标帧率，并跟踪它的变化
+#[derive(Debug, Clone)]
+pub struct TargetFpsEstimator {
+    config: TargetFpsConfig,
+    frametimes: VecDeque<Duration>,
+    current: Option<TargetFps>,
+    // 等待确认的新帧率、刷新率和它们已经保持的帧数
+    pending: Option<(u32, u32, usize)>,
+}
+
+impl Default for TargetFpsEstimator {
+    fn default() -> Self {
+        Self::new(TargetFpsConfig::default())
+    }
+}
+
+impl TargetFpsEstimator {
+    #[must_use]
+    pub fn new(config: TargetFpsConfig) -> Self {
+        Self {
+            frametimes: VecDeque::with_capacity(config.window),
+            config,
+            current: None,
+            pending: None,
+        }
+    }
+
+    /// 记录一帧，目标帧率或刷新率发生变化时返回新的结果
+    pub fn push(&mut self, frametime: Duration) -> Option<TargetFps> {
+        if self.frametimes.len() >= self.config.window.max(1) {
+            self.frametimes.pop_front();
+        }
+        self.frametimes.push_back(frametime);
+
+        if self.frametimes.len() < self.config.min_frames {
+            return None;
+        }
+        let estimate = self.estimate()?;
+
+        // 帧率和刷新率都不变时只更新置信度
+        if let Some(current) = &mut self.current
+            && (current.fps, current.refresh_rate) == (estimate.fps, estimate.refresh_rate)
+        {
+            *current = estimate;
+            self.pending = None;
+            return None;
+        }
+
+        let held = match self.pending {
+            Some((fps, refresh_rate, held))
+                if (fps, refresh_rate) == (estimate.fps, estimate.refresh_rate) =>
+            {
+                held + 1
+            }
+            _ => 1,
+        };
+        if held < self.config.hold_frames || estimate.confidence < self.config.min_confidence {
+            self.pending = Some((estimate.fps, estimate.refresh_rate, held));
+            return None;
+        }
+
+        self.pending = None;
+        self.current = Some(estimate);
+        self.current
+    }
+
+    /// 最近一次确认的结果
+    #[must_use]
+    pub const fn current(&self) -> Option<TargetFps> {
+        self.current
+    }
+
+    /// 只根据当前窗口推断，不考虑切换条件
+    #[must_use]
+    pub fn estimate(&self) -> Option<TargetFps> {
+        infer(
+            self.frametimes.iter().copied(),
+            &self.config.candidates,
+            self.config.tolerance,
+        )
+    }
+
+    pub fn reset(&mut self) {
+        self.frametimes.clear();
+        self.current = None;
+        self.pending = None;
+    }
+}
+
+// 根据一段帧时间推断目标帧率和刷新率
+pub(crate) fn infer(
+    frametimes: impl Iterator<Item = Duration>,
+    candidates: &[u32],
+    tolerance: f64,
+) -> Option<TargetFps> {
+    let mut sorted: Vec<_> = frametimes.collect();
+    sorted.sort_unstable();
+
+    let median = *sorted.get(sorted.len() / 2)?;
+    if median.is_zero() {
+        return None;
+    }
+
+    let measured = 1.0 / median.as_secs_f64();
+    let fps = *candidates
+        .iter()
+        .find(|fps| f64::from(**fps) >= measured * (1.0 - tolerance))
+        .or_else(|| candidates.last())?;
+
+    // 所有簇都落在vsync周期整数倍附近的最低刷新率，没有候选满足时认为与帧率相同
+    let clusters = clusters(&sorted, tolerance);
+    let refresh_rate = candidates
+        .iter()
+        .filter(|refresh_rate| **refresh_rate >= fps)
+        .find(|refresh_rate| {
+            let vsync = 1.0 / f64::from(**refresh_rate);
+            clusters.iter().all(|center| {
+                let multiple = center / vsync;
+                multiple.round() >= 1.0 && (multiple - multiple.round()).abs() <= tolerance
+            })
+        })
+        .copied()
+        .unwrap_or(fps);
+
+    let period = 1.0 / f64::from(fps);
+    let hits = sorted
+        .iter()
+        .filter(|frametime| (frametime.as_secs_f64() - period).abs() <= period * tolerance)
+        .count();
+
+    Some(TargetFps {
+        fps,
+        refresh_rate,
+        confidence: hits as f32 / sorted.len() as f32,
+    })
+}
+
+// 把升序的帧时间按相邻间隔切成簇，返回每簇的平均帧时间(s)
+// 帧数太少的簇是偶发的异常帧，不参与推断
+fn clusters(sorted: &[Duration], tolerance: f64) -> Vec<f64> {
+    let min_len = (sorted.len() / 50).max(2);
+    let mut centers = Vec::new();
+    let mut start = 0;
+    for end in 1..=sorted.len() {
+        let split = end == sorted.len()
+            || sorted[end].as_secs_f64() > sorted[end - 1].as_secs_f64() * (1.0 + tolerance);
+        if !split {
+            continue;
+        }
+
+        let cluster = &sorted[start..end];
+        if cluster.len() >= min_len {
+            centers.push(cluster.iter().sum::<Duration>().as_secs_f64() / cluster.len() as f64);
+        }
+        start = end;
+    }
+    centers
+}
+
+/// 每个应用的每个surface各自一个[`TargetFpsEstimator`]
+#[derive(Debug, Clone, Default)]
+pub struct SurfaceTargetFps {
+    config: TargetFpsConfig,
+    estimators: PerSurface<TargetFpsEstimator>,
+}
+
+impl SurfaceTargetFps {
+    #[must_use]
+    pub fn new(config: TargetFpsConfig) -> Self {
+        Self {
+            config,
+            estimators: PerSurface::default(),
+        }
+    }
+
+    /// 记录一帧，可能合并了两帧的帧和空闲后恢复的帧不参与推断
+    pub fn push(&mut self, event: &FrameEvent) -> Option<TargetFps> {
+        self.estimators
+            .entry(event, || TargetFpsEstimator::new(self.config.clone()))?
+            .push(event.frametime)
+    }
+
+    #[must_use]
+    pub fn current(&self, pid: Pid, surface: SurfaceId) -> Option<TargetFps> {
+        self.estimators.get(pid, surface)?.current()
+    }
+
+    /// 删除该应用所有surface的推断状态
+    pub fn remove(&mut self, pid: Pid) {
+        self.estimators.remove(pid);
+    }
+
+    pub fn clear(&mut self) {
+        self.estimators.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 在目标帧时间附近按固定模式抖动±5%
+    fn frames(fps: u32, count: usize) -> impl Iterator<Item = Duration> {
+        let period = 1.0 / f64::from(fps);
+        (0..count).map(move |i| Duration::from_secs_f64(period * [1.0, 0.95, 1.05, 1.0][i % 4]))
+    }
+
+    fn changes(
+        estimator: &mut TargetFpsEstimator,
+        frametimes: impl Iterator<Item = Duration>,
+    ) -> Vec<u32> {
+        frametimes
+            .filter_map(|frametime| estimator.push(frametime))
+            .map(|target| target.fps)
+            .collect()
+    }
+
+    fn refresh_changes(
+        estimator: &mut TargetFpsEstimator,
+        frametimes: impl Iterator<Item = Duration>,
+    ) -> Vec<(u32, u32)> {
+        frametimes
+            .filter_map(|frametime| estimator.push(frametime))
+            .map(|target| (target.fps, target.refresh_rate))
+            .collect()
+    }
+
+    #[test]
+    fn detects_target_and_changes() {
+        let mut estimator = TargetFpsEstimator::default();
+        assert_eq!(changes(&mut estimator, frames(60, 200)), [60]);
+
+        let current = estimator.current().unwrap();
+        assert_eq!(current.refresh_rate, 60);
+        assert!(current.confidence > 0.99);
+
+        // 进入菜单后锁30帧，窗口中一半是新帧时中位数翻转，再保持30帧才切换
+        // 窗口中还有60帧的帧时，它们说明了刷新率是60，全部移出窗口后无法再区分
+        let switches: Vec<_> = frames(30, 200)
+            .enumerate()
+            .filter_map(|(i, frametime)| estimator.push(frametime).map(|target| (i, target)))
+            .map(|(i, target)| (i, target.fps, target.refresh_rate))
+            .collect();
+        assert_eq!(switches, [(59 + 29, 30, 60), (118 + 29, 30, 30)]);
+
+        assert_eq!(changes(&mut estimator, frames(144, 300)), [144]);
+    }
+
+    // 以fps为目标在refresh_rate的屏幕上运行，每隔8帧早一个vsync上屏，下一帧补回来
+    fn paced(fps: u32, refresh_rate: u32, count: usize) -> impl Iterator<Item = Duration> {
+        let vsync = 1.0 / f64::from(refresh_rate);
+        let multiple = f64::from(refresh_rate / fps);
+        (0..count).map(move |i| {
+            let multiple = match i % 8 {
+                6 => multiple - 1.0,
+                7 => multiple + 1.0,
+                _ => multiple,
+            };
+            Duration::from_secs_f64(vsync * multiple)
+        })
+    }
+
+    #[test]
+    fn refresh_rate_differs_from_target() {
+        for (fps, refresh_rate) in [(30, 60), (30, 120), (60, 120), (45, 90)] {
+            let mut estimator = TargetFpsEstimator::default();
+            assert_eq!(
+                refresh_changes(&mut estimator, paced(fps, refresh_rate, 200)),
+                [(fps, refresh_rate)]
+            );
+        }
+    }
+
+    #[test]
+    fn refresh_rate_change_is_reported() {
+        let mut estimator = TargetFpsEstimator::default();
+        // 同样锁30帧，屏幕从60Hz切到120Hz
+        assert_eq!(
+            refresh_changes(&mut estimator, paced(30, 60, 200)),
+            [(30, 60)]
+        );
+        assert_eq!(
+            refresh_changes(&mut estimator, paced(30, 120, 200)),
+            [(30, 120)]
+        );
+    }
+
+    #[test]
+    fn occasional_jank_does_not_change_target() {
+        let mut estimator = TargetFpsEstimator::default();
+        let frametimes = frames(90, 300).enumerate().map(|(i, frametime)| {
+            if i % 10 == 0 {
+                frametime * 3
+            } else {
+                frametime
+            }
+        });
+        assert_eq!(changes(&mut estimator, frametimes), [90]);
+        // 刷新率由最快的一簇帧决定，不低于目标帧率
+        assert_eq!(estimator.current().unwrap().refresh_rate, 90);
+        assert!(estimator.current().unwrap().confidence > 0.85);
+    }
+}
@@ -15,43 +15,30 @@
 * along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use aya::{
-    Ebpf,
-    maps::{MapData, RingBuf},
-    programs::UProbe,
-};
+use aya::programs::{UProbe, uprobe::UProbeLink};
 use std::path::{Path, PathBuf};
-use crate::{ebpf::load_bpf, error::Result, error::AnalyzerError};
-
-// 抑制未使用代码警告（后续会使用则保留，否则可删除字段/方法）
-#[allow(dead_code)]
-pub struct UprobeHandler {
-    bpf: Ebpf,
-    pid: i32,
-    libgui_path: PathBuf, // 仅用于重试附加的路径缓存
-}
+use crate::{
+    ebpf::PROGRAM, error::Result, error::AnalyzerError, process::mapped_library, symbols::LibrarySymbols,
+    report::{AttachAttempt, AttachFailure, AttachReport},
+};
 
-impl Drop for UprobeHandler {
-    fn drop(&mut self) {
-        // 自动卸载探针，释放eBPF资源（修正：添加合法注释标记）
-        if let Ok(program) = self.get_program() {
-            let _ = program.unload();
-        }
-    }
+const LIBGUI: &str = "libgui.so";
+
+/// 附加探针时尝试的库路径和符号，按顺序尝试直到成功
+///
+/// 优先附加从库的符号表中找到的queueBuffer，`symbols`只在一个都找不到时使用。
+/// 附加到单个应用时优先使用它实际映射的libgui，`libgui_paths`只作为后备
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    pub libgui_paths: Vec<PathBuf>,
+    pub symbols: Vec<String>,
+    pub proc_root: PathBuf,
 }
 
-impl UprobeHandler {
-    /// 核心：附加目标应用的queueBuffer Uprobe探针
-    pub fn attach_app(pid: i32) -> Result<Self> {
-        let mut bpf = load_bpf()?;
-        // 加载并转换为Uprobe程序：直接用?，自动转换ProgramError
-        let program: &mut UProbe = bpf.program_mut("frame_analyzer_ebpf")
-            .ok_or(AnalyzerError::MapError)?
-            .try_into()?; // 关键：移除map_err，利用#[from]自动转换
-        program.load()?;
-
+impl Default for ProbeConfig {
+    fn default() -> Self {
         // Android不同版本的queueBuffer符号适配（核心符号列表）
-        let queue_buffer_symbols = [
+        let symbols = [
             "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferi",
             "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferiPNS_24SurfaceQueueBufferOutputE",
             "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferj",
@@ -66,74 +53,184 @@ impl UprobeHandler {
             "/vendor/lib64/libgui.so",
         ];
 
-        // 遍历路径和符号，尝试附加探针
-        let mut target_lib_path = PathBuf::new();
-        let mut attach_success = false;
-        'outer: for &path in &libgui_paths {
-            let lib_path = Path::new(path);
-            if !lib_path.exists() {
-                continue;
-            }
-
-            for &symbol in &queue_buffer_symbols {
-                if program.attach(Some(symbol), 0, lib_path, Some(pid)).is_ok() {
-                    target_lib_path = lib_path.to_path_buf();
-                    attach_success = true;
-                    break 'outer;
-                }
-            }
+        Self {
+            libgui_paths: libgui_paths.into_iter().map(PathBuf::from).collect(),
+            symbols: symbols.into_iter().map(String::from).collect(),
+            proc_root: PathBuf::from("/proc"),
         }
+    }
+}
 
-        if !attach_success {
-            return Err(AnalyzerError::AppNotFound);
-        }
+// 抑制未使用代码警告（后续会使用则保留，否则可删除字段/方法）
+#[allow(dead_code)]
+pub struct UprobeHandler {
+    links: Vec<UProbeLink>, // drop时自动卸载探针，共享的eBPF程序不受影响
+    pid: Option<i32>, // None表示附加到所有进程
+    libgui_path: PathBuf, // 实际附加的库路径，也用于重试附加
+    symbols: Vec<String>, // 实际附加成功的符号
+    report: AttachReport, // 最近一次附加的全部尝试
+}
+
+impl UprobeHandler {
+    /// 核心：在共享的eBPF程序上附加目标应用的queueBuffer Uprobe探针
+    pub fn attach_app(program: &mut UProbe, config: &ProbeConfig, pid: i32) -> Result<Self> {
+        Self::attach(program, config, Some(pid))
+    }
 
-        Ok(Self {
-            bpf,
-            pid,
-            libgui_path: target_lib_path,
-        })
+    /// 附加到系统中所有进程，由内核中的`PID_FILTER`决定上报哪些进程
+    pub fn attach_system(program: &mut UProbe, config: &ProbeConfig) -> Result<Self> {
+        Self::attach(program, config, None)
     }
 
-    /// 核心：应用重启后重试附加探针
-    #[allow(dead_code)] // 抑制方法未使用警告
-    pub fn reattach(&mut self) -> Result<()> {
-        // 提前缓存pid和libgui_path，避免借用冲突
-        let pid = self.pid;
-        let libgui_path = self.libgui_path.clone();
+    /// 实际附加成功的queueBuffer符号
+    pub fn symbols(&self) -> &[String] {
+        &self.symbols
+    }
 
-        let program = self.get_program()?;
-        let _ = program.unload(); // 卸载旧探针
+    pub const fn report(&self) -> &AttachReport {
+        &self.report
+    }
 
-        // 重试核心符号
-        let queue_buffer_symbols = [
-            "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferi",
-            "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferiPNS_24SurfaceQueueBufferOutputE",
-        ];
+    fn attach(program: &mut UProbe, config: &ProbeConfig, pid: Option<i32>) -> Result<Self> {
+        let mapped = pid.and_then(|pid| mapped_library(&config.proc_root, pid, LIBGUI));
+        let mut report = AttachReport::new(pid);
+
+        // 遍历路径，尝试附加探针
+        for lib_path in mapped.iter().chain(&config.libgui_paths) {
+            let (links, symbols) = attach_library(program, config, lib_path, pid, &mut report)?;
+            if !links.is_empty() {
+                return Ok(Self {
+                    links,
+                    pid,
+                    libgui_path: lib_path.clone(),
+                    symbols,
+                    report,
+                });
+            }
+        }
+
+        Err(AnalyzerError::AttachFailed(report))
+    }
 
-        // 尝试重新附加
-        for &symbol in &queue_buffer_symbols {
-            if program.attach(Some(symbol), 0, &libgui_path, Some(pid)).is_ok() {
+    /// 核心：应用以新pid重启后重试附加探针
+    ///
+    /// 旧路径可能在旧进程的`/proc/<pid>/root`下，所以先重新解析新进程映射的库
+    pub fn reattach(&mut self, program: &mut UProbe, config: &ProbeConfig, pid: i32) -> Result<()> {
+        let mapped = mapped_library(&config.proc_root, pid, LIBGUI);
+        let candidates: Vec<_> = mapped.into_iter().chain([self.libgui_path.clone()]).collect();
+        let mut report = AttachReport::new(Some(pid));
+
+        for lib_path in candidates {
+            let (links, symbols) =
+                attach_library(program, config, &lib_path, Some(pid), &mut report)?;
+            if !links.is_empty() {
+                // 新链接替换旧链接时旧探针随之卸载
+                self.links = links;
+                self.symbols = symbols;
+                self.libgui_path = lib_path;
+                self.pid = Some(pid);
+                self.report = report;
                 return Ok(());
             }
         }
 
-        Err(AnalyzerError::AppNotFound)
+        Err(AnalyzerError::AttachFailed(report))
+    }
+}
+
+// 附加到ELF中找到的所有queueBuffer重载，一个都没有时才按静态列表逐个尝试
+// 每次尝试都记录到report中
+// 一个重载转调另一个时两个符号都会触发，由eBPF程序按线程在入口去重
+fn attach_library(
+    program: &mut UProbe,
+    config: &ProbeConfig,
+    lib_path: &Path,
+    pid: Option<i32>,
+    report: &mut AttachReport,
+) -> Result<(Vec<UProbeLink>, Vec<String>)> {
+    let mut links = Vec::new();
+    let mut picked = Vec::new();
+
+    if !lib_path.exists() {
+        report.file_missing(lib_path.to_path_buf());
+        return Ok((links, picked));
+    }
+
+    // 静态列表中的符号也解析偏移，报告中可以区分符号不存在和附加失败
+    let library = LibrarySymbols::read(lib_path, &config.symbols);
+    let discovered: Vec<_> = library.queue_buffer().map(String::from).collect();
+    let fallback = discovered.is_empty();
+    let candidates = if fallback { &config.symbols } else { &discovered };
+
+    for symbol in candidates {
+        let failure = match program.attach(Some(symbol), 0, lib_path, pid) {
+            Ok(link_id) => {
+                let link = program.take_link(link_id).map_err(|source| {
+                    AnalyzerError::Program {
+                        name: PROGRAM,
+                        source,
+                    }
+                })?;
+                links.push(link);
+                picked.push(symbol.clone());
+                None
+            }
+            Err(error) => Some(AttachFailure::from_program_error(&error)),
+        };
+
+        let attached = failure.is_none();
+        report.attempts.push(AttachAttempt {
+            library: lib_path.to_path_buf(),
+            symbol: Some(symbol.clone()),
+            offset: library.offset(symbol),
+            failure,
+        });
+
+        // 静态列表中的符号是同一个函数在不同版本中的签名，只需要一个
+        if fallback && attached {
+            break;
+        }
+    }
+
+    Ok((links, picked))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+
+    use super::*;
+    use crate::{Pid, builder::page_size, ebpf::FrameBpf};
+
+    // 模拟libgui中的两个重载，不带output的版本转调带output的版本
+    #[unsafe(export_name = "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferiPNS_24SurfaceQueueBufferOutputE")]
+    #[inline(never)]
+    extern "C" fn queue_buffer_with_output(surface: u64, _fence: i32, _output: usize) -> u64 {
+        black_box(surface)
     }
 
-    /// 核心：获取eBPF的RingBuf，读取采集的帧数据
-    pub fn ring(&mut self) -> Result<RingBuf<&mut MapData>> {
-        let ring = RingBuf::try_from(self.bpf.map_mut("RING_BUF")
-            .ok_or(AnalyzerError::MapError)?)?;
-        Ok(ring)
+    #[unsafe(export_name = "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferi")]
+    #[inline(never)]
+    extern "C" fn queue_buffer(surface: u64, fence: i32) -> u64 {
+        black_box(queue_buffer_with_output(surface, fence, 0))
     }
 
-    /// 内部：获取Uprobe程序实例
-    fn get_program(&mut self) -> Result<&mut UProbe> {
-        let program = self.bpf.program_mut("frame_analyzer_ebpf")
-            .ok_or(AnalyzerError::MapError)?;
-        // 关键：移除map_err，直接用?自动转换
-        let uprobe: &mut UProbe = program.try_into()?;
-        Ok(uprobe)
+    #[test]
+    #[ignore = "needs CAP_BPF"]
+    fn nested_overloads_report_one_frame() {
+        let mut bpf = FrameBpf::load(page_size()).unwrap();
+        let config = ProbeConfig {
+            libgui_paths: vec![PathBuf::from("/proc/self/exe")],
+            ..ProbeConfig::default()
+        };
+        let handler = UprobeHandler::attach_system(bpf.program().unwrap(), &config).unwrap();
+        assert_eq!(handler.symbols().len(), 2);
+        bpf.allow(std::process::id() as Pid).unwrap();
+
+        queue_buffer(0x1234, -1);
+        queue_buffer_with_output(0x5678, -1, 0);
+        queue_buffer(0x9abc, -1);
+        let buffers: Vec<_> = bpf.drain().iter().map(|signal| signal.buffer).collect();
+        assert_eq!(buffers, [0x1234, 0x5678, 0x9abc]);
     }
 }
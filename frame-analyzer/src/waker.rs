@@ -0,0 +1,49 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::sync::Arc;
+
+use mio::{Registry, Token, Waker};
+
+use crate::error::Result;
+
+/// 从其它线程唤醒阻塞在[`Analyzer::recv_event`](crate::Analyzer::recv_event)上的分析器
+///
+/// 可以任意克隆，所有克隆唤醒的是同一个分析器
+#[derive(Debug, Clone)]
+pub struct AnalyzerWaker {
+    waker: Arc<Waker>,
+}
+
+impl AnalyzerWaker {
+    // 每个poll只能有一个mio::Waker，所以只在创建分析器时调用一次
+    pub(crate) fn new(registry: &Registry, token: Token) -> Result<Self> {
+        let waker = Arc::new(Waker::new(registry, token)?);
+        Ok(Self { waker })
+    }
+
+    /// 让正在阻塞的接收返回[`AnalyzerEvent::Woken`](crate::AnalyzerEvent::Woken)
+    ///
+    /// 没有阻塞中的接收时，下一次阻塞接收会立即返回
+    ///
+    /// # Errors
+    ///
+    /// 写入内部的eventfd失败
+    pub fn wake(&self) -> Result<()> {
+        self.waker.wake()?;
+        Ok(())
+    }
+}